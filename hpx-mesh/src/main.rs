@@ -14,11 +14,15 @@ use hpx_app::{App, Config};
 use hpx_context::ctx::{Forward, GTX};
 use hpx_context::Context;
 use hpx_forward::proxy;
-use hpx_register::register_server;
+use hpx_health::health_check;
+use hpx_middleware::middleware::current_request_id;
+use hpx_register::{register_server, watch_route_file};
 use hpx_signal as signal;
 use hyper::http::Request;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Server};
+use std::io::Write;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::Arc;
@@ -35,7 +39,23 @@ fn main() {
         port,
         ..
     } = app;
-    env_logger::from_env(Env::default().default_filter_or(level)).init();
+    env_logger::from_env(Env::default().default_filter_or(level))
+        .format(|buf, record| {
+            // lines logged while serving a request carry its id
+            let id = current_request_id()
+                .map(|id| format!(" request_id={}", id))
+                .unwrap_or_default();
+            writeln!(
+                buf,
+                "[{} {} {}] {}{}",
+                buf.timestamp(),
+                buf.default_styled_level(record.level()),
+                record.module_path().unwrap_or_default(),
+                record.args(),
+                id
+            )
+        })
+        .init();
     rt::build(worker_thread).block_on(async move {
         let gtx = GTX {
             inner: Arc::new(
@@ -47,15 +67,18 @@ fn main() {
         let static_ctx: &'static GTX = unsafe { std::mem::transmute(&gtx) };
         let (shutdown_tx, mut shutdown_rx) = mpsc::unbounded_channel::<()>();
         let socket_addr = &SocketAddr::new(IpAddr::from_str("0.0.0.0").unwrap(), port);
-        let server = Server::bind(&socket_addr)
+        let server = Server::bind(socket_addr)
             .http1_keepalive(true)
             .tcp_nodelay(true)
-            .serve(make_service_fn(move |_| async move {
-                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| async move {
-                    let ctx = static_ctx.inner.clone();
-                    let route = ctx.get_route();
-                    proxy(ctx, route, req).await
-                }))
+            .serve(make_service_fn(move |conn: &AddrStream| {
+                let remote_addr = conn.remote_addr();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| async move {
+                        let ctx = static_ctx.inner.clone();
+                        let route = ctx.get_route();
+                        proxy(ctx, route, remote_addr, req).await
+                    }))
+                }
             }))
             .with_graceful_shutdown(async {
                 shutdown_rx.recv().await;
@@ -69,11 +92,13 @@ fn main() {
                     std::process::exit(1)
                 }
             };
+            let health = health_check(static_ctx);
+            let route_file = watch_route_file(static_ctx);
             let signal = async move {
                 signal::shutdown().await;
-                shutdown_tx.send(())
+                let _ = shutdown_tx.send(());
             };
-            join!(route_serve, signal);
+            join!(route_serve, health, route_file, signal);
         };
 
         select!(_=Box::pin(server.fuse())=>(), _=Box::pin(side_future.fuse())=>());
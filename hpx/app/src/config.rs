@@ -7,11 +7,30 @@ pub struct Config {
     pub env_code: String,
     pub connect_timeout: usize,
     pub keepalive_timeout: usize,
+    /// seconds to wait for the upstream response, `0` waits forever
+    pub request_timeout: usize,
+    /// seconds the upstream response body may stall, `0` disables it
+    pub idle_timeout: usize,
+    /// bytes a request body may carry, `0` disables the limit
+    pub max_body_size: usize,
+    /// installed routes kept for rollback
+    pub route_history: usize,
+    /// file the installed routes are saved to and restored from on startup
+    pub state_file: Option<String>,
+    /// YAML or JSON file of static routes, reloaded on change
+    pub route_file: Option<String>,
+    /// proxies in front whose forwarding headers are kept, `0` replaces them
+    pub trusted_hops: usize,
 }
 
 const DEFAULT_SAMPLING_PERCENTAGE: usize = 0;
 const DEFAULT_CONNECT_TIMEOUT: usize = 30;
 const DEFAULT_KEEPALIVE_TIMEOUT: usize = 60;
+const DEFAULT_REQUEST_TIMEOUT: usize = 60;
+const DEFAULT_IDLE_TIMEOUT: usize = 0;
+const DEFAULT_MAX_BODY_SIZE: usize = 0;
+const DEFAULT_ROUTE_HISTORY: usize = 10;
+const DEFAULT_TRUSTED_HOPS: usize = 0;
 
 impl Config {
     pub fn init() -> Self {
@@ -19,6 +38,13 @@ impl Config {
         let percentage = parse_env_num("SAMPLING_PERCENTAGE", DEFAULT_SAMPLING_PERCENTAGE);
         let connect_timeout = parse_env_num("CONNECT_TIMEOUT", DEFAULT_CONNECT_TIMEOUT);
         let keepalive_timeout = parse_env_num("KEEPALIVE_TIMEOUT", DEFAULT_KEEPALIVE_TIMEOUT);
+        let request_timeout = parse_env_num("REQUEST_TIMEOUT", DEFAULT_REQUEST_TIMEOUT);
+        let idle_timeout = parse_env_num("IDLE_TIMEOUT", DEFAULT_IDLE_TIMEOUT);
+        let max_body_size = parse_env_num("MAX_BODY_SIZE", DEFAULT_MAX_BODY_SIZE);
+        let route_history = parse_env_num("ROUTE_HISTORY", DEFAULT_ROUTE_HISTORY);
+        let state_file = env::var("STATE_FILE").ok();
+        let route_file = env::var("ROUTE_FILE").ok();
+        let trusted_hops = parse_env_num("TRUSTED_HOPS", DEFAULT_TRUSTED_HOPS);
         let env_code = env::var("ENV_CODE").expect("ENV_CODE is empty!");
         Self {
             tracing_udp: udp,
@@ -26,6 +52,13 @@ impl Config {
             env_code,
             connect_timeout,
             keepalive_timeout,
+            request_timeout,
+            idle_timeout,
+            max_body_size,
+            route_history,
+            state_file,
+            route_file,
+            trusted_hops,
         }
     }
 }
@@ -1,6 +1,6 @@
-use crate::ContextState;
+use crate::{persist, ContextState};
 use hpx_app::Config;
-use hpx_route::Route;
+use hpx_route::{Route, RouteSnapshot};
 use hpx_sampling::{random_set, DEFAULT_RESERVOIR_SIZE};
 use hpx_tracing::{start_tracing, Tracing};
 use hyper::client::{Client, HttpConnector, ResponseFuture};
@@ -8,8 +8,9 @@ use hyper::http::header::CONTENT_TYPE;
 use hyper::http::{Request, Version};
 use hyper::Body;
 use mick_jaeger::TracesIn;
+use std::collections::VecDeque;
 use std::sync::atomic::AtomicUsize;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
 pub struct GTX {
@@ -18,10 +19,15 @@ pub struct GTX {
 
 pub struct Context {
     route: RwLock<Arc<Route>>,
+    /// the last installed routes, oldest first
+    history: Mutex<VecDeque<RouteSnapshot>>,
+    /// version of the route last written to the state file
+    saved: Arc<Mutex<u64>>,
     h1_client: Client<HttpConnector, Body>,
     h2_client: Client<HttpConnector, Body>,
     trace_in: Option<Arc<TracesIn>>,
     state: ContextState,
+    conf: Config,
 }
 
 impl Context {
@@ -31,6 +37,8 @@ impl Context {
         connector.set_nodelay(true);
         let mut ctx = Self {
             route: RwLock::default(),
+            history: Mutex::default(),
+            saved: Arc::default(),
             h1_client: Client::builder()
                 .pool_idle_timeout(Some(Duration::from_secs(5)))
                 .build(connector.clone()),
@@ -43,9 +51,22 @@ impl Context {
                 sampling: random_set(DEFAULT_RESERVOIR_SIZE, conf.sampling_percentage),
                 counter: AtomicUsize::new(0),
             },
+            conf,
         };
-        if let Some(udp) = conf.tracing_udp {
-            let trace_in = start_tracing(udp.as_str(), conf.env_code.as_str()).await?;
+        if let Some(path) = &ctx.conf.state_file {
+            match persist::load(path).and_then(|ep| Route::from_endpoints(&ep)) {
+                Ok(route) => {
+                    info!("Restored {} servants from {}", route.servant.len(), path);
+                    let route = ctx.install(&mut ctx.route.write().unwrap(), route);
+                    // the state file already holds it
+                    *ctx.saved.lock().unwrap() = route.version;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Restore routes from {} error: {:?}", path, e),
+            }
+        }
+        if let Some(udp) = &ctx.conf.tracing_udp {
+            let trace_in = start_tracing(udp.as_str(), ctx.conf.env_code.as_str()).await?;
             ctx.trace_in = Some(trace_in);
         }
 
@@ -55,15 +76,67 @@ impl Context {
     pub fn get_state(&self) -> &ContextState {
         &self.state
     }
+
+    pub fn get_config(&self) -> &Config {
+        &self.conf
+    }
+
+    /// Version the route after the current one and record it in the history,
+    /// returns the installed route.
+    fn install(&self, current: &mut Arc<Route>, mut route: Route) -> Arc<Route> {
+        route.version = current.version + 1;
+        let mut history = self.history.lock().unwrap();
+        history.push_back(RouteSnapshot::new(&route));
+        while history.len() > self.conf.route_history.max(1) {
+            history.pop_front();
+        }
+        *current = Arc::new(route);
+        current.clone()
+    }
+
+    /// Save an installed route to the state file off the runtime threads,
+    /// unless a newer route was saved first.
+    fn persist(&self, route: Arc<Route>) {
+        let path = match &self.conf.state_file {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let saved = self.saved.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut saved = saved.lock().unwrap();
+            if *saved >= route.version {
+                return;
+            }
+            match persist::save(&path, &route.endpoints) {
+                Ok(()) => *saved = route.version,
+                Err(e) => error!("Save routes to {} error: {:?}", path, e),
+            }
+        });
+    }
 }
 
 pub trait Forward {
     fn get_route(&self) -> Arc<Route>;
-    fn reload_route(&self, route: Route);
+    /// Replace the route with one built from the current route. The route is
+    /// built without holding the lock and built again when another update got
+    /// installed meanwhile. Returns the installed version.
+    fn update_route(
+        &self,
+        update: &dyn Fn(&Route) -> std::io::Result<Route>,
+    ) -> std::io::Result<u64>;
+    /// Snapshots of the last installed routes, oldest first.
+    fn route_history(&self) -> Vec<RouteSnapshot>;
     fn forward_to(&self, req: Request<Body>) -> ResponseFuture;
 }
 pub trait SendTrace: Forward {
-    fn send_tracing(&self, trace: Tracing, status_code: u16, target: &str, msg: &str);
+    fn send_tracing(
+        &self,
+        trace: Tracing,
+        status_code: u16,
+        target: &str,
+        msg: &str,
+        tags: &[(&str, &str)],
+    );
 }
 
 impl Forward for Arc<Context> {
@@ -72,9 +145,26 @@ impl Forward for Arc<Context> {
         (*lock).clone()
     }
 
-    fn reload_route(&self, route: Route) {
-        let mut lock = self.route.write().unwrap();
-        *lock = Arc::new(route);
+    fn update_route(
+        &self,
+        update: &dyn Fn(&Route) -> std::io::Result<Route>,
+    ) -> std::io::Result<u64> {
+        loop {
+            let current = self.get_route();
+            let route = update(&current)?;
+            let mut lock = self.route.write().unwrap();
+            if lock.version != current.version {
+                continue;
+            }
+            let route = self.install(&mut lock, route);
+            drop(lock);
+            self.persist(route.clone());
+            return Ok(route.version);
+        }
+    }
+
+    fn route_history(&self) -> Vec<RouteSnapshot> {
+        self.history.lock().unwrap().iter().cloned().collect()
     }
 
     fn forward_to(&self, req: Request<Body>) -> ResponseFuture {
@@ -82,7 +172,7 @@ impl Forward for Arc<Context> {
             && req
                 .headers()
                 .get(CONTENT_TYPE)
-                .map_or(false, |x| x.to_str().unwrap_or("").eq("application/grpc"))
+                .is_some_and(|x| x.to_str().unwrap_or("").eq("application/grpc"))
         {
             return self.h2_client.request(req);
         }
@@ -91,7 +181,14 @@ impl Forward for Arc<Context> {
 }
 
 impl SendTrace for Arc<Context> {
-    fn send_tracing(&self, trace: Tracing, status_code: u16, target: &str, msg: &str) {
+    fn send_tracing(
+        &self,
+        trace: Tracing,
+        status_code: u16,
+        target: &str,
+        msg: &str,
+        tags: &[(&str, &str)],
+    ) {
         if let Some(trace_in) = &self.trace_in {
             let mut _span = trace_in.span_with_id_and_parent(
                 trace.trace_id,
@@ -100,7 +197,10 @@ impl SendTrace for Arc<Context> {
                 target,
             );
             _span.add_int_tag("status_code", status_code as i64);
-            if msg.len() > 0 {
+            for (key, value) in tags {
+                _span.add_string_tag(key, value);
+            }
+            if !msg.is_empty() {
                 _span.log().with_string("message", msg);
             }
         }
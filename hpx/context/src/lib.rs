@@ -1,4 +1,8 @@
+#[macro_use]
+extern crate log;
+
 pub mod ctx;
+mod persist;
 mod state;
 
 pub use ctx::Context;
@@ -0,0 +1,116 @@
+use hpx_route::{EndpointsMap, ServiceRoute};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Read the registration payload saved by `save`.
+pub(crate) fn load(path: &str) -> io::Result<EndpointsMap> {
+    let file = fs::File::open(path)?;
+    let routes: Vec<ServiceRoute> = serde_json::from_reader(io::BufReader::new(file))?;
+    ServiceRoute::validate(&routes).map_err(io::Error::other)
+}
+
+/// Write the registration payload of `ep` to `path`, through a temporary file
+/// renamed over it so a crash never leaves a half written file behind. Both
+/// the file and the rename are synced to disk before returning.
+pub(crate) fn save(path: &str, ep: &EndpointsMap) -> io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let payload = serde_json::to_vec_pretty(&ServiceRoute::from_endpoints(ep))?;
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(&payload)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, save};
+    use crate::ctx::{Context, Forward};
+    use hpx_app::Config;
+    use hpx_route::{EndpointsMap, ServiceRoute};
+    use serde_json::json;
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+
+    /// A state file path in a fresh directory of its own.
+    fn state_file(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hpx-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("routes.json")
+    }
+
+    fn endpoints(servant: &str) -> EndpointsMap {
+        let routes = json!([{"servant": servant, "endpoints": ["10.0.0.1:80"],
+            "routes": [{"path": "/a", "kind": "precise"}]}]);
+        let routes: Vec<ServiceRoute> = serde_json::from_value(routes).unwrap();
+        ServiceRoute::validate(&routes).unwrap()
+    }
+
+    #[test]
+    fn saves_replace_the_file_through_a_temporary_one() {
+        let path = state_file("save");
+        let path = path.to_str().unwrap();
+        // left over by a crash while saving
+        fs::write(format!("{}.tmp", path), "{").unwrap();
+        save(path, &endpoints("a")).unwrap();
+        save(path, &endpoints("b")).unwrap();
+        assert!(fs::metadata(format!("{}.tmp", path)).is_err());
+        let restored = load(path).unwrap();
+        assert_eq!(restored.keys().collect::<Vec<_>>(), vec!["b"]);
+        assert_eq!(restored["b"].routes[0].path, "/a");
+    }
+
+    #[test]
+    fn missing_and_corrupt_files_fail_to_load() {
+        let path = state_file("load");
+        let path = path.to_str().unwrap();
+        assert_eq!(load(path).unwrap_err().kind(), io::ErrorKind::NotFound);
+        fs::write(path, "[{\"servant\": ").unwrap();
+        assert_eq!(load(path).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        fs::write(path, "not json").unwrap();
+        assert_eq!(load(path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // valid JSON that doesn't validate
+        fs::write(path, r#"[{"servant": "a", "endpoints": [], "routes": []}]"#).unwrap();
+        assert!(load(path).is_err());
+    }
+
+    fn restored(path: &str) -> u64 {
+        let conf = Config {
+            tracing_udp: None,
+            sampling_percentage: 0,
+            env_code: "test".into(),
+            connect_timeout: 1,
+            keepalive_timeout: 1,
+            request_timeout: 0,
+            idle_timeout: 0,
+            max_body_size: 0,
+            route_history: 1,
+            state_file: Some(path.to_owned()),
+            route_file: None,
+            trusted_hops: 0,
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let ctx = runtime.block_on(Context::with_config(conf)).unwrap();
+        std::sync::Arc::new(ctx).get_route().version
+    }
+
+    #[test]
+    fn startup_restores_only_a_valid_file() {
+        let path = state_file("restore");
+        let path = path.to_str().unwrap();
+        assert_eq!(restored(path), 0);
+        fs::write(path, "not json").unwrap();
+        assert_eq!(restored(path), 0);
+        save(path, &endpoints("a")).unwrap();
+        assert_eq!(restored(path), 1);
+    }
+}
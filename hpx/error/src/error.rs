@@ -7,6 +7,9 @@ pub struct AppResponseError {
     pub code: u16,
     #[serde(rename = "message")]
     pub message: String,
+    /// the request field the error is about
+    #[serde(rename = "field", default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
     #[serde(skip_serializing, skip_deserializing)]
     pub status_code: StatusCode,
 }
@@ -16,7 +19,18 @@ impl AppResponseError {
         AppResponseError {
             code,
             message: s.into(),
+            field: None,
             status_code,
         }
     }
+
+    /// A 400 about one field of the request.
+    pub fn invalid_field(field: String, s: &str) -> Self {
+        AppResponseError {
+            code: StatusCode::BAD_REQUEST.as_u16(),
+            message: s.into(),
+            field: Some(field),
+            status_code: StatusCode::BAD_REQUEST,
+        }
+    }
 }
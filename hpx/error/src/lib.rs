@@ -6,12 +6,83 @@ use hyper::http::{HeaderValue, Response, StatusCode};
 use hyper::Body;
 
 pub fn not_found() -> Response<Body> {
-    let err = AppResponseError::from(
+    json_error(AppResponseError::from(
         StatusCode::NOT_FOUND.as_u16(),
         "route not found",
         StatusCode::NOT_FOUND,
+    ))
+}
+
+pub fn gateway_timeout() -> Response<Body> {
+    json_error(AppResponseError::from(
+        StatusCode::GATEWAY_TIMEOUT.as_u16(),
+        "upstream request timeout",
+        StatusCode::GATEWAY_TIMEOUT,
+    ))
+}
+
+pub fn bad_gateway() -> Response<Body> {
+    json_error(AppResponseError::from(
+        StatusCode::BAD_GATEWAY.as_u16(),
+        "invalid upstream address",
+        StatusCode::BAD_GATEWAY,
+    ))
+}
+
+/// 503 for a request whose servant has no endpoint able to take it, all of
+/// them being unhealthy, ejected or drained.
+pub fn no_healthy_upstream() -> Response<Body> {
+    json_error(AppResponseError::from(
+        StatusCode::SERVICE_UNAVAILABLE.as_u16(),
+        "no healthy upstream",
+        StatusCode::SERVICE_UNAVAILABLE,
+    ))
+}
+
+/// 400 for a request whose `Content-Length` isn't a number.
+pub fn invalid_content_length() -> Response<Body> {
+    json_error(AppResponseError::from(
+        StatusCode::BAD_REQUEST.as_u16(),
+        "invalid content-length",
+        StatusCode::BAD_REQUEST,
+    ))
+}
+
+pub fn payload_too_large() -> Response<Body> {
+    json_error(AppResponseError::from(
+        StatusCode::PAYLOAD_TOO_LARGE.as_u16(),
+        "request body too large",
+        StatusCode::PAYLOAD_TOO_LARGE,
+    ))
+}
+
+/// 503 for a request shed by a circuit breaker, marked with
+/// `x-hpx-overloaded: true` so clients can tell it from an upstream 503.
+pub fn overloaded() -> Response<Body> {
+    let mut resp = json_error(AppResponseError::from(
+        StatusCode::SERVICE_UNAVAILABLE.as_u16(),
+        "upstream overloaded",
+        StatusCode::SERVICE_UNAVAILABLE,
+    ));
+    resp.headers_mut()
+        .insert("x-hpx-overloaded", HeaderValue::from_static("true"));
+    resp
+}
+
+/// 400 listing every invalid field of the request.
+pub fn invalid_fields(errors: &[AppResponseError]) -> Response<Body> {
+    let b = serde_json::to_string(errors).unwrap_or_else(|_| String::from("[]"));
+    let mut resp = Response::new(Body::from(b));
+    *resp.status_mut() = StatusCode::BAD_REQUEST;
+    resp.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/json; charset=UTF-8"),
     );
-    let b = serde_json::to_string(&err).unwrap_or(String::from("route not found"));
+    resp
+}
+
+fn json_error(err: AppResponseError) -> Response<Body> {
+    let b = serde_json::to_string(&err).unwrap_or_else(|_| err.message.clone());
     let mut resp = Response::new(Body::from(b));
     *resp.status_mut() = err.status_code;
     resp.headers_mut().insert(
@@ -28,6 +99,13 @@ pub fn bad_request(err: String) -> Response<Body> {
         .unwrap()
 }
 
+pub fn precondition_failed(err: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::PRECONDITION_FAILED)
+        .body(Body::from(err))
+        .unwrap()
+}
+
 pub fn status_ok() -> Response<Body> {
     Response::builder()
         .status(StatusCode::OK)
@@ -0,0 +1,275 @@
+use crate::headers::Upstream;
+use hyper::http::header::{
+    AsHeaderName, HeaderName, CONNECTION, FORWARDED, HOST, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION,
+    TE, TRAILER, TRANSFER_ENCODING, UPGRADE, VIA,
+};
+use hyper::http::{HeaderMap, HeaderValue, Request, Response, StatusCode, Version};
+use hyper::Body;
+use std::net::SocketAddr;
+
+pub const X_FORWARDED_FOR: &str = "x-forwarded-for";
+pub const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+pub const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+const KEEP_ALIVE: &str = "keep-alive";
+const PROXY_CONNECTION: &str = "proxy-connection";
+
+/// Prepare a request for the upstream by RFC 7230: drop its hop-by-hop
+/// headers and record the client and this hop in the forwarding headers.
+/// Forwarding headers written by up to `trusted_hops` proxies in front are
+/// kept, anything the client claimed before them is dropped.
+pub(crate) fn request(req: &mut Request<Body>, peer: SocketAddr, trusted_hops: usize) {
+    let upgrade = req.headers().contains_key(UPGRADE);
+    let version = req.version();
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().authority().map(|a| a.as_str()))
+        .map(str::to_owned);
+    let headers = req.headers_mut();
+    strip_hop_by_hop(headers, upgrade);
+
+    let client = peer.ip().to_string();
+    let mut xff = trusted(headers, X_FORWARDED_FOR, trusted_hops);
+    xff.push(client.clone());
+    set(
+        headers,
+        HeaderName::from_static(X_FORWARDED_FOR),
+        &xff.join(", "),
+    );
+
+    let node = match peer {
+        SocketAddr::V4(_) => client,
+        SocketAddr::V6(_) => format!("\"[{}]\"", client),
+    };
+    let mut element = format!("for={};proto=http", node);
+    if let Some(host) = &host {
+        element.push_str(&format!(";host={}", quoted(host)));
+    }
+    let mut forwarded = trusted(headers, &FORWARDED, trusted_hops);
+    forwarded.push(element);
+    set(headers, FORWARDED, &forwarded.join(", "));
+
+    if trusted_hops == 0 || !headers.contains_key(X_FORWARDED_PROTO) {
+        set(headers, HeaderName::from_static(X_FORWARDED_PROTO), "http");
+    }
+    if trusted_hops == 0 || !headers.contains_key(X_FORWARDED_HOST) {
+        match &host {
+            Some(host) => set(headers, HeaderName::from_static(X_FORWARDED_HOST), host),
+            None => {
+                headers.remove(X_FORWARDED_HOST);
+            }
+        }
+    }
+    append_via(headers, version);
+}
+
+/// Drop the hop-by-hop headers of an upstream response, those of a
+/// `101 Switching Protocols` are kept for the client to switch too.
+pub(crate) fn response(resp: &mut Response<Body>) {
+    if resp.status() == StatusCode::SWITCHING_PROTOCOLS {
+        return;
+    }
+    strip_hop_by_hop(resp.headers_mut(), false);
+    if resp.extensions().get::<Upstream>().is_some() {
+        let version = resp.version();
+        append_via(resp.headers_mut(), version);
+    }
+}
+
+/// Remove `Connection`, the headers it lists and the standard hop-by-hop
+/// headers. Upgrades keep `Upgrade` and an `upgrade` connection option, `TE`
+/// survives when it only asks for trailers, as gRPC does.
+fn strip_hop_by_hop(headers: &mut HeaderMap, upgrade: bool) {
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>();
+    for name in &listed {
+        if upgrade && name == "upgrade" {
+            continue;
+        }
+        if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+            headers.remove(name);
+        }
+    }
+    let trailers = headers.get_all(TE).iter().all(|v| {
+        v.to_str()
+            .is_ok_and(|v| v.trim().eq_ignore_ascii_case("trailers"))
+    });
+    headers.remove(CONNECTION);
+    headers.remove(KEEP_ALIVE);
+    headers.remove(PROXY_CONNECTION);
+    headers.remove(PROXY_AUTHENTICATE);
+    headers.remove(PROXY_AUTHORIZATION);
+    headers.remove(TRAILER);
+    headers.remove(TRANSFER_ENCODING);
+    if !trailers {
+        headers.remove(TE);
+    }
+    if upgrade {
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    } else {
+        headers.remove(UPGRADE);
+    }
+}
+
+/// The elements of a list header added by the last `hops` proxies.
+fn trusted(headers: &HeaderMap, name: impl AsHeaderName, hops: usize) -> Vec<String> {
+    let mut elements = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|e| e.trim().to_owned())
+        .filter(|e| !e.is_empty())
+        .collect::<Vec<_>>();
+    let skip = elements.len().saturating_sub(hops);
+    elements.drain(..skip);
+    elements
+}
+
+fn append_via(headers: &mut HeaderMap, version: Version) {
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    if let Ok(via) = HeaderValue::from_str(&format!("{} hpx", protocol)) {
+        headers.append(VIA, via);
+    }
+}
+
+fn set(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    match HeaderValue::from_str(value) {
+        Ok(value) => {
+            headers.insert(name, value);
+        }
+        Err(_) => {
+            headers.remove(name);
+        }
+    }
+}
+
+/// A `Forwarded` parameter value, quoted unless it's a token.
+fn quoted(value: &str) -> String {
+    let token = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    match token {
+        true => value.to_owned(),
+        false => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn forwarded(pairs: &[(&'static str, &'static str)], trusted_hops: usize) -> HeaderMap {
+        let mut req = Request::new(Body::empty());
+        *req.headers_mut() = headers(pairs);
+        request(&mut req, "10.0.0.9:5000".parse().unwrap(), trusted_hops);
+        req.headers().clone()
+    }
+
+    #[test]
+    fn strips_hop_by_hop_and_connection_listed_headers() {
+        let mut map = headers(&[
+            ("connection", "close, x-hop"),
+            ("x-hop", "1"),
+            ("keep-alive", "timeout=5"),
+            ("proxy-authorization", "basic x"),
+            ("transfer-encoding", "chunked"),
+            ("te", "gzip"),
+            ("upgrade", "websocket"),
+            ("x-end", "1"),
+        ]);
+        strip_hop_by_hop(&mut map, false);
+        let names = map.keys().map(|k| k.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["x-end"]);
+    }
+
+    #[test]
+    fn keeps_te_trailers_and_upgrades() {
+        let mut map = headers(&[
+            ("connection", "Upgrade, x-hop"),
+            ("x-hop", "1"),
+            ("upgrade", "websocket"),
+            ("te", "trailers"),
+        ]);
+        strip_hop_by_hop(&mut map, true);
+        assert_eq!(map["connection"], "upgrade");
+        assert_eq!(map["upgrade"], "websocket");
+        assert_eq!(map["te"], "trailers");
+        assert!(!map.contains_key("x-hop"));
+    }
+
+    #[test]
+    fn trusted_keeps_the_last_hops() {
+        let map = headers(&[
+            ("x-forwarded-for", "1.1.1.1, 2.2.2.2"),
+            ("x-forwarded-for", "3.3.3.3"),
+        ]);
+        assert!(trusted(&map, X_FORWARDED_FOR, 0).is_empty());
+        assert_eq!(
+            trusted(&map, X_FORWARDED_FOR, 2),
+            vec!["2.2.2.2", "3.3.3.3"]
+        );
+        assert_eq!(trusted(&map, X_FORWARDED_FOR, 5).len(), 3);
+    }
+
+    #[test]
+    fn client_claims_are_replaced_without_trusted_hops() {
+        let map = forwarded(
+            &[
+                ("host", "api.example"),
+                ("x-forwarded-for", "6.6.6.6"),
+                ("x-forwarded-proto", "https"),
+                ("forwarded", "for=6.6.6.6"),
+            ],
+            0,
+        );
+        assert_eq!(map["x-forwarded-for"], "10.0.0.9");
+        assert_eq!(map["x-forwarded-proto"], "http");
+        assert_eq!(map["x-forwarded-host"], "api.example");
+        assert_eq!(map["forwarded"], "for=10.0.0.9;proto=http;host=api.example");
+        assert_eq!(map["via"], "1.1 hpx");
+    }
+
+    #[test]
+    fn trusted_proxies_are_kept() {
+        let map = forwarded(
+            &[
+                ("host", "api.example"),
+                ("x-forwarded-for", "6.6.6.6, 1.2.3.4"),
+                ("x-forwarded-proto", "https"),
+            ],
+            1,
+        );
+        assert_eq!(map["x-forwarded-for"], "1.2.3.4, 10.0.0.9");
+        assert_eq!(map["x-forwarded-proto"], "https");
+    }
+
+    #[test]
+    fn quotes_non_token_values() {
+        assert_eq!(quoted("api.example"), "api.example");
+        assert_eq!(quoted("api.example:8080"), "\"api.example:8080\"");
+        assert_eq!(quoted("a\"b"), "\"a\\\"b\"");
+    }
+}
@@ -1,49 +1,104 @@
-use crate::{Respond, RespondKind};
+use crate::timeout::UpstreamTimeout;
+use crate::{Matched, Respond, RespondKind};
 
-#[macro_use]
-use hpx_middleware::middleware;
 use hpx_middleware::middleware::{
-    is_sampling, parse_trace, sampling_rate_ctl, with_body_size_limit, with_print, with_trace,
+    in_request_scope, is_sampling, parse_trace, request_id, with_body_size_limit, with_request_id,
+    REQUEST_ID, X_REQUEST_ID,
 };
+use hpx_middleware::pipeline::pipeline;
 use hpx_route::Route;
 use hyper::http::header::UPGRADE;
-use hyper::http::{HeaderMap, HeaderValue, Request, Response, StatusCode};
+use hyper::http::{HeaderValue, Request, Response, StatusCode};
 use hyper::Body;
 
-use std::num::{NonZeroU128, NonZeroU64};
-
-use hpx_context::ctx::{Forward, SendTrace};
+use hpx_context::ctx::SendTrace;
 use hpx_context::Context;
-use hpx_tracing::{set_tracing_header, Tracing, X_PARENT_ID, X_SAMPLING, X_SPAN_ID, X_TRACE_ID};
-use serde::de::Unexpected::Bytes;
+use hpx_tracing::{set_tracing_header, Tracing};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Instant;
 
 pub async fn proxy(
     ctx: Arc<Context>,
     route: Arc<Route>,
+    remote_addr: SocketAddr,
     mut req: Request<Body>,
 ) -> Result<Response<Body>, hyper::Error> {
-    let (mut_req, ctx_ref) = (&mut req, &ctx);
-    if let Err(e) = middleware!(
-        mut_req,
-        ctx_ref,
-        with_print,
-        with_body_size_limit,
-        sampling_rate_ctl,
-        with_trace
-    ) {
-        return Ok(to_response(e.code, e.message));
+    with_request_id(&mut req);
+    let id = request_id(&req);
+    // the rule is matched once, before middlewares see the request
+    let matched = route.find(&req).map(|m| (m.index, m.params));
+    let rule = matched.as_ref().map(|(index, _)| &route.rules[*index].path);
+    let mut response = match with_body_size_limit(&ctx, rule, &mut req) {
+        Some(rejected) => rejected,
+        None => {
+            REQUEST_ID
+                .scope(id.clone(), serve(ctx, route, remote_addr, req, matched))
+                .await?
+        }
+    };
+    if let Ok(id) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(X_REQUEST_ID, id);
     }
+    Ok(response)
+}
+
+/// Pass the request through the middleware pipeline of its route on the way
+/// to the upstream.
+async fn serve(
+    ctx: Arc<Context>,
+    route: Arc<Route>,
+    remote_addr: SocketAddr,
+    req: Request<Body>,
+    matched: Matched,
+) -> Result<Response<Body>, hyper::Error> {
+    let pipeline = pipeline(&route, matched.as_ref().map(|(index, _)| *index));
+    let next_ctx = ctx.clone();
+    pipeline
+        .run(&ctx, req, move |req| {
+            forward(next_ctx, route, remote_addr, req, matched)
+        })
+        .await
+}
+
+async fn forward(
+    ctx: Arc<Context>,
+    route: Arc<Route>,
+    remote_addr: SocketAddr,
+    mut req: Request<Body>,
+    matched: Matched,
+) -> Result<Response<Body>, hyper::Error> {
+    let mut_req = &mut req;
     let (sampling, trace) = (is_sampling(mut_req), parse_trace(mut_req));
-    let respond = Respond::from_kind(to_respond_kind(ctx.clone(), route, req));
+    let (method, path, start, id) = (
+        req.method().clone(),
+        req.uri().path().to_owned(),
+        Instant::now(),
+        request_id(&req),
+    );
+    let kind = to_respond_kind(ctx.clone(), route, remote_addr, req, matched);
+    let respond = Respond::from_kind(kind);
+    let (target, params) = (respond.target.clone(), respond.params.clone());
 
-    Ok(trace_respond(ctx, sampling, trace, respond).await?)
+    let response = trace_respond(ctx, sampling, trace, id, respond).await?;
+    debug!(
+        "Access {} {} {} -> {} {} {:?} [{}]",
+        remote_addr,
+        method,
+        path,
+        target.as_deref().unwrap_or("-"),
+        response.status().as_u16(),
+        start.elapsed(),
+        params
+    );
+    Ok(response)
 }
 
 async fn trace_respond(
     ctx: Arc<Context>,
     sampling: bool,
     trace: Tracing,
+    request_id: String,
     respond: Respond,
 ) -> Result<Response<Body>, hyper::Error> {
     let target = respond.target.clone();
@@ -52,32 +107,51 @@ async fn trace_respond(
         return Ok(response);
     }
     let mut body_bytes = bytes::Bytes::new();
-    if !response.status().is_success() {
+    if !response.status().is_success() && !response.status().is_informational() {
         let (part, body) = response.into_parts();
         body_bytes = hyper::body::to_bytes(body).await?;
         response = Response::from_parts(part, Body::from(body_bytes.clone()));
     }
     let status_code = response.status_mut().as_u16();
+    let timed_out = response.extensions().get::<UpstreamTimeout>().is_some();
     if let Some(t) = target {
-        tokio::spawn(async move {
-            send_tracing(ctx, trace, t.as_str(), status_code, body_bytes.to_vec()).await;
-        });
+        tokio::spawn(in_request_scope(async move {
+            let mut tags = vec![("request_id", request_id.as_str())];
+            if timed_out {
+                tags.push(("timeout", "true"));
+            }
+            send_tracing(
+                ctx,
+                trace,
+                t.as_str(),
+                status_code,
+                body_bytes.to_vec(),
+                &tags,
+            )
+            .await;
+        }));
     }
     set_tracing_header(trace, sampling, response.headers_mut());
 
     Ok(response)
 }
 
-fn to_respond_kind(ctx: Arc<Context>, route: Arc<Route>, req: Request<Body>) -> RespondKind {
+fn to_respond_kind(
+    ctx: Arc<Context>,
+    route: Arc<Route>,
+    peer: SocketAddr,
+    req: Request<Body>,
+    matched: Matched,
+) -> RespondKind {
     match req.headers().get(UPGRADE) {
-        Some(_) => RespondKind::Upgrade(ctx, route, req),
-        None => RespondKind::Forward(ctx, route, req),
+        Some(_) => RespondKind::Upgrade(ctx, route, peer, req, matched),
+        None => RespondKind::Forward(ctx, route, peer, req, matched),
     }
 }
 
 pub(crate) fn to_response(code: u16, message: String) -> Response<Body> {
     let mut respond = Response::new(Body::from(message.to_string()));
-    *respond.status_mut() = StatusCode::from_u16(code).unwrap_or(StatusCode::default());
+    *respond.status_mut() = StatusCode::from_u16(code).unwrap_or_default();
     respond
 }
 
@@ -87,6 +161,7 @@ async fn send_tracing(
     target: &str,
     status_code: u16,
     buf: Vec<u8>,
+    tags: &[(&str, &str)],
 ) {
     unsafe {
         ctx.send_tracing(
@@ -94,6 +169,7 @@ async fn send_tracing(
             status_code,
             target,
             String::from_utf8_unchecked(buf).as_str(),
+            tags,
         )
     }
 }
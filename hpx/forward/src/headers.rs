@@ -0,0 +1,99 @@
+use hpx_middleware::middleware::X_REQUEST_ID;
+use hpx_route::{HeaderRules, Route, RoutePath, Servant};
+use hpx_tracing::X_TRACE_ID;
+use hyper::http::{HeaderMap, Request, Response};
+use hyper::Body;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Address of the server a response came from.
+#[derive(Clone, Debug)]
+pub(crate) struct Upstream(pub String);
+
+/// The header rules of a request's servant and route, with the values of
+/// the variables they may use.
+pub(crate) struct HeaderRewrite {
+    route: Arc<Route>,
+    rule: usize,
+    servant: usize,
+    client_ip: String,
+    trace_id: Option<String>,
+    request_id: Option<String>,
+}
+
+impl HeaderRewrite {
+    /// `None` when neither the servant nor the route change headers.
+    pub fn new(
+        route: &Arc<Route>,
+        rule: usize,
+        servant: usize,
+        peer: SocketAddr,
+        req: &Request<Body>,
+    ) -> Option<Arc<Self>> {
+        let (path, target) = (&route.rules[rule].path, &route.servant[servant]);
+        if [
+            &target.request_headers,
+            &target.response_headers,
+            &path.request_headers,
+            &path.response_headers,
+        ]
+        .iter()
+        .all(|rules| rules.is_empty())
+        {
+            return None;
+        }
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+        };
+        Some(Arc::new(Self {
+            route: route.clone(),
+            rule,
+            servant,
+            client_ip: peer.ip().to_string(),
+            trace_id: header(X_TRACE_ID),
+            request_id: header(X_REQUEST_ID),
+        }))
+    }
+
+    /// Apply the request rules for the request forwarded to `upstream`.
+    pub fn request(&self, req: &mut Request<Body>, upstream: &str) {
+        let (servant, path) = self.rules();
+        self.apply(
+            &[&servant.request_headers, &path.request_headers],
+            req.headers_mut(),
+            Some(upstream),
+        );
+    }
+
+    pub fn response(&self, resp: &mut Response<Body>) {
+        let (servant, path) = self.rules();
+        let upstream = resp.extensions().get::<Upstream>().map(|u| u.0.clone());
+        self.apply(
+            &[&servant.response_headers, &path.response_headers],
+            resp.headers_mut(),
+            upstream.as_deref(),
+        );
+    }
+
+    fn rules(&self) -> (&Servant, &RoutePath) {
+        (
+            &self.route.servant[self.servant],
+            &self.route.rules[self.rule].path,
+        )
+    }
+
+    fn apply(&self, rules: &[&HeaderRules], headers: &mut HeaderMap, upstream: Option<&str>) {
+        let var = |name: &str| match name {
+            "client_ip" => Some(self.client_ip.clone()),
+            "servant" => Some(self.route.servant[self.servant].name.clone()),
+            "upstream" => upstream.map(str::to_owned),
+            "trace_id" => self.trace_id.clone(),
+            "request_id" => self.request_id.clone(),
+            _ => None,
+        };
+        rules.iter().for_each(|r| r.apply(headers, &var));
+    }
+}
@@ -2,95 +2,417 @@
 extern crate log;
 
 use std::future::Future;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::str::FromStr;
-use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::task::Poll;
+use std::time::Duration;
 
-use hpx_error::not_found;
-use hpx_route::{Route, Servant, Server};
+use hpx_error::{bad_gateway, no_healthy_upstream, not_found, overloaded, payload_too_large};
+use hpx_middleware::middleware::BodyLimit;
+use hpx_route::{Admission, InFlight, PathParams, Route, Server};
+use hyper::body::HttpBody;
 use hyper::client::ResponseFuture;
 use hyper::http::{Request, Response, StatusCode, Uri};
 use hyper::Body;
+use rand::Rng;
 
+mod forwarded;
 mod handle;
+mod headers;
+mod mirror;
+mod retry;
+mod rewrite;
+#[cfg(test)]
+mod testing;
+mod timeout;
+mod upgrade;
+use crate::headers::{HeaderRewrite, Upstream};
+use crate::timeout::{pump, with_timeout};
 use crate::to_response;
+use crate::upgrade::upgrade;
+pub use forwarded::{X_FORWARDED_FOR, X_FORWARDED_HOST, X_FORWARDED_PROTO};
 pub use handle::*;
 use hpx_context::ctx::Forward;
 use hpx_context::Context;
+pub use rewrite::X_ORIGINAL_PATH;
+
+type ResponseInner = Pin<Box<dyn Future<Output = Result<Response<Body>, hyper::Error>> + Send>>;
 
 struct Respond {
     target: Option<String>,
-    inner: Pin<Box<dyn Future<Output = Result<Response<Body>, hyper::Error>> + Send>>,
+    /// variables captured by the matched route's path
+    params: PathParams,
+    inner: ResponseInner,
+    /// keeps the request pending on its servant's circuit breaker until the
+    /// response body ended
+    admission: Option<Admission>,
+    /// seconds the response body may stall, `0` disables it
+    idle: u64,
+    /// set when the request body is counted against a size limit
+    body_limit: Option<BodyLimit>,
+    headers: Option<Arc<HeaderRewrite>>,
 }
 
+/// The index of the route rule a request matched and the variables its path
+/// captured, `None` when it matched none.
+type Matched = Option<(usize, PathParams)>;
+
 enum RespondKind {
-    Forward(Arc<Context>, Arc<Route>, Request<Body>),
-    Upgrade(Arc<Context>, Arc<Route>, Request<Body>),
+    Forward(Arc<Context>, Arc<Route>, SocketAddr, Request<Body>, Matched),
+    Upgrade(Arc<Context>, Arc<Route>, SocketAddr, Request<Body>, Matched),
 }
 
-impl Respond {
-    pub fn new(inner: ResponseFuture, target: &str) -> Self {
+/// A single attempt to forward a request to one server, its outcome is
+/// reported to outlier detection once the upstream answered. The request
+/// stays in flight on the server until the response body ended, the response
+/// carries the `InFlight` along for it.
+struct Attempt {
+    target: String,
+    inner: ResponseFuture,
+    in_flight: Option<InFlight>,
+}
+
+impl Attempt {
+    pub fn new(inner: ResponseFuture, target: &str, in_flight: InFlight) -> Self {
         Self {
-            inner: Box::pin(inner),
-            target: Some(target.to_owned()),
+            inner,
+            target: target.to_owned(),
+            in_flight: Some(in_flight),
         }
     }
 }
 
+impl Future for Attempt {
+    type Output = Result<Response<Body>, hyper::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+        let mut result = futures::ready!(Pin::new(&mut self.inner).poll(cx));
+        if let Some(in_flight) = self.in_flight.take() {
+            let failed = match &result {
+                Ok(resp) => resp.status().is_server_error(),
+                Err(e) => e.is_connect(),
+            };
+            if let Some(ejection) = in_flight.record(failed) {
+                warn!(
+                    "Outlier {} {} ejected for {:?}",
+                    self.target,
+                    in_flight.addr(),
+                    ejection
+                );
+            }
+            if let Ok(resp) = &mut result {
+                let upstream = Upstream(in_flight.addr().to_owned());
+                resp.extensions_mut().insert(upstream);
+                resp.extensions_mut().insert(in_flight);
+            }
+        }
+        Poll::Ready(result)
+    }
+}
+
 impl Future for Respond {
     type Output = Response<Body>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
-        match Pin::new(&mut self.inner).poll(cx) {
-            Poll::Ready(result) => match result {
-                Ok(resp) => Poll::Ready(resp),
-                Err(e) => {
-                    error!("Forward to {:?} error: {:?}", self.target, e);
-                    Poll::Ready(to_response(
-                        StatusCode::SERVICE_UNAVAILABLE.as_u16(),
-                        e.to_string(),
-                    ))
-                }
-            },
-            Poll::Pending => Poll::Pending,
+        let mut resp = match futures::ready!(Pin::new(&mut self.inner).poll(cx)) {
+            Ok(resp) => resp,
+            Err(_) if self.body_limit.as_ref().is_some_and(|l| l.exceeded()) => {
+                warn!("Request body to {:?} over the size limit", self.target);
+                payload_too_large()
+            }
+            Err(e) => {
+                error!("Forward to {:?} error: {:?}", self.target, e);
+                to_response(StatusCode::SERVICE_UNAVAILABLE.as_u16(), e.to_string())
+            }
+        };
+        forwarded::response(&mut resp);
+        if let Some(headers) = &self.headers {
+            headers.response(&mut resp);
         }
+        // the request holds its admission and server until the body ended,
+        // upgraded connections are left alone
+        let held = (
+            resp.extensions_mut().remove::<InFlight>(),
+            self.admission.take(),
+        );
+        let holding = held.0.is_some() || held.1.is_some();
+        if (self.idle > 0 || holding)
+            && !resp.body().is_end_stream()
+            && resp.status() != StatusCode::SWITCHING_PROTOCOLS
+        {
+            let target = self.target.clone().unwrap_or_default();
+            let idle = Some(Duration::from_secs(self.idle)).filter(|_| self.idle > 0);
+            let body = std::mem::take(resp.body_mut());
+            *resp.body_mut() = pump(target, body, idle, held);
+        }
+        Poll::Ready(resp)
     }
 }
 
 impl Respond {
     pub fn from_kind(kind: RespondKind) -> Self {
         match kind {
-            RespondKind::Forward(ctx, route, mut req) => {
-                let servant_idx = match route.rmap.get(req.uri().path()) {
-                    Some(index) => index,
-                    None => match route.rtrie.get_ancestor_value(req.uri().path()) {
-                        Some(index) => index,
-                        None => {
-                            return Respond {
-                                target: None,
-                                inner: Box::pin(futures::future::ok(not_found())),
-                            };
-                        }
-                    },
+            RespondKind::Forward(ctx, route, peer, mut req, matched) => {
+                forwarded::request(&mut req, peer, ctx.get_config().trusted_hops);
+                let body_limit = req.extensions().get::<BodyLimit>().cloned();
+                let (rule_index, params) = match matched {
+                    Some(matched) => matched,
+                    None => return Respond::not_found(),
                 };
-                let s: &Servant = &route.servant[*servant_idx];
-                let count = s.state.count.fetch_add(1, Ordering::SeqCst);
-                let server: &Server = &s.servers[count % (s.servers.len())];
-                let forward_uri = match req.uri().query() {
-                    Some(query) => format!("http://{}{}?{}", server.addr, req.uri().path(), query),
-                    None => format!("http://{}{}", server.addr, req.uri().path()),
+                let rule = &route.rules[rule_index];
+                rewrite::rewrite(&rule.path, &params, &mut req);
+                let index = rule.pick_servant(&req, peer);
+                let servant = &route.servant[index];
+                let headers = HeaderRewrite::new(&route, rule_index, index, peer, &req);
+                let admission = match servant.state.admit() {
+                    Some(admission) => admission,
+                    None => return Respond::overloaded(&servant.name),
                 };
-                *req.uri_mut() = Uri::from_str(forward_uri.as_str()).unwrap();
+                if let (Some(mirror), Some(config)) = (rule.mirror, &rule.path.mirror) {
+                    if rand::thread_rng().gen_range(0, 100) < config.percent {
+                        mirror::mirror(&ctx, &route, rule_index, mirror, peer, &mut req);
+                    }
+                }
+                let conf = ctx.get_config();
+                let timeout = rule.path.timeout.unwrap_or(conf.request_timeout as u64);
+                let idle = rule.path.idle_timeout.unwrap_or(conf.idle_timeout as u64);
+                let inner: ResponseInner = match rule.path.retry.clone() {
+                    Some(policy) => Box::pin(retry::forward(
+                        ctx,
+                        route.clone(),
+                        index,
+                        policy,
+                        peer,
+                        req,
+                        headers.clone(),
+                    )),
+                    None => {
+                        let slot = match servant.state.reserve_request() {
+                            Some(slot) => slot,
+                            None => return Respond::overloaded(&servant.name),
+                        };
+                        let (server, in_flight) = match servant.select(&req, peer, &[], slot) {
+                            Some(s) => s,
+                            None => return Respond::unavailable(&servant.name),
+                        };
+                        *req.uri_mut() = match forward_uri(server, &req) {
+                            Some(uri) => uri,
+                            None => return Respond::bad_gateway(&servant.name),
+                        };
+                        if let Some(headers) = &headers {
+                            headers.request(&mut req, &server.addr);
+                        }
+                        let name = servant.name.as_str();
+                        Box::pin(Attempt::new(ctx.forward_to(req), name, in_flight))
+                    }
+                };
+                let name = servant.name.clone();
                 Respond {
-                    target: Some(s.name.clone()),
-                    inner: Box::pin(ctx.forward_to(req)),
+                    target: Some(servant.name.clone()),
+                    params,
+                    inner: Box::pin(async move { with_timeout(&name, inner, timeout).await }),
+                    admission: Some(admission),
+                    idle,
+                    body_limit,
+                    headers,
                 }
             }
-            RespondKind::Upgrade(_, _, _) => {
-                unreachable!()
+            RespondKind::Upgrade(ctx, route, peer, mut req, matched) => {
+                forwarded::request(&mut req, peer, ctx.get_config().trusted_hops);
+                let (rule_index, params) = match matched {
+                    Some(matched) => matched,
+                    None => return Respond::not_found(),
+                };
+                let rule = &route.rules[rule_index];
+                rewrite::rewrite(&rule.path, &params, &mut req);
+                let index = rule.pick_servant(&req, peer);
+                let headers = HeaderRewrite::new(&route, rule_index, index, peer, &req);
+                let servant = &route.servant[index];
+                let admission = match servant.state.admit() {
+                    Some(admission) => admission,
+                    None => return Respond::overloaded(&servant.name),
+                };
+                let slot = match servant.state.reserve_request() {
+                    Some(slot) => slot,
+                    None => return Respond::overloaded(&servant.name),
+                };
+                let (server, in_flight) = match servant.select(&req, peer, &[], slot) {
+                    Some(s) => s,
+                    None => return Respond::unavailable(&servant.name),
+                };
+                *req.uri_mut() = match forward_uri(server, &req) {
+                    Some(uri) => uri,
+                    None => return Respond::bad_gateway(&servant.name),
+                };
+                if let Some(headers) = &headers {
+                    headers.request(&mut req, &server.addr);
+                }
+                let upstream = Upstream(server.addr.clone());
+                let handshake = upgrade(ctx, servant.name.clone(), req);
+                Respond {
+                    target: Some(servant.name.clone()),
+                    params,
+                    inner: Box::pin(async move {
+                        let mut resp = handshake.await;
+                        drop(in_flight);
+                        if let Ok(resp) = &mut resp {
+                            resp.extensions_mut().insert(upstream);
+                        }
+                        resp
+                    }),
+                    admission: Some(admission),
+                    idle: 0,
+                    body_limit: None,
+                    headers,
+                }
             }
         }
     }
+
+    fn not_found() -> Self {
+        Respond {
+            target: None,
+            params: PathParams::default(),
+            inner: Box::pin(futures::future::ok(not_found())),
+            admission: None,
+            idle: 0,
+            body_limit: None,
+            headers: None,
+        }
+    }
+
+    fn bad_gateway(target: &str) -> Self {
+        Respond {
+            target: Some(target.to_owned()),
+            params: PathParams::default(),
+            inner: Box::pin(futures::future::ok(bad_gateway())),
+            admission: None,
+            idle: 0,
+            body_limit: None,
+            headers: None,
+        }
+    }
+
+    fn unavailable(target: &str) -> Self {
+        warn!("No healthy server of {}, request rejected", target);
+        Respond {
+            target: Some(target.to_owned()),
+            params: PathParams::default(),
+            inner: Box::pin(futures::future::ok(no_healthy_upstream())),
+            admission: None,
+            idle: 0,
+            body_limit: None,
+            headers: None,
+        }
+    }
+
+    fn overloaded(target: &str) -> Self {
+        warn!("Circuit breaker of {} open, request rejected", target);
+        Respond {
+            target: Some(target.to_owned()),
+            params: PathParams::default(),
+            inner: Box::pin(futures::future::ok(overloaded())),
+            admission: None,
+            idle: 0,
+            body_limit: None,
+            headers: None,
+        }
+    }
+}
+
+/// The URI of the request on `server`, `None` when the server's address
+/// doesn't make one.
+pub(crate) fn forward_uri(server: &Server, req: &Request<Body>) -> Option<Uri> {
+    let forward_uri = match req.uri().query() {
+        Some(query) => format!("http://{}{}?{}", server.addr, req.uri().path(), query),
+        None => format!("http://{}{}", server.addr, req.uri().path()),
+    };
+    match Uri::from_str(forward_uri.as_str()) {
+        Ok(uri) => Some(uri),
+        Err(e) => {
+            error!("Invalid upstream uri {}: {:?}", forward_uri, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy;
+    use crate::testing::{block_on, context, read_head, route};
+    use hyper::{body, Body, Request, StatusCode};
+    use serde_json::json;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+
+    #[test]
+    fn unmatched_is_404_and_no_server_is_503() {
+        block_on(async {
+            let ctx = context().await;
+            // the only weighted server goes down, leaving the drained one
+            let route = route(json!([{"servant": "drained",
+                "routes": [{"path": "/a", "kind": "precise"}],
+                "endpoints": [{"addr": "127.0.0.1:9", "weight": 1}, {"addr": "127.0.0.1:10", "weight": 0}],
+                "health_check": {"path": "/health", "interval": 5, "timeout": 2,
+                    "healthy_threshold": 1, "unhealthy_threshold": 1}}]));
+            let servant = &route.servant[0];
+            let check = servant.health_check.as_ref().unwrap();
+            servant.state.servers[0].record_probe(false, check);
+            let peer = "127.0.0.1:40000".parse().unwrap();
+            let get = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+            let resp = proxy(ctx.clone(), route.clone(), peer, get("/b"))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+            let resp = proxy(ctx, route, peer, get("/a")).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert!(resp.headers().get("x-hpx-overloaded").is_none());
+            let body = body::to_bytes(resp.into_body()).await.unwrap();
+            assert_eq!(
+                &body[..],
+                br#"{"code":503,"message":"no healthy upstream"}"#
+            );
+        });
+    }
+
+    #[test]
+    fn requests_stay_in_flight_until_the_body_ended() {
+        block_on(async {
+            // an upstream streaming its body in two chunks, the second one
+            // once told to
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (finish, finished) = oneshot::channel::<()>();
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                read_head(&mut stream).await;
+                let head = "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n2\r\nab\r\n";
+                stream.write_all(head.as_bytes()).await.unwrap();
+                finished.await.unwrap();
+                stream.write_all(b"2\r\ncd\r\n0\r\n\r\n").await.unwrap();
+            });
+
+            let ctx = context().await;
+            let route = route(
+                json!([{"servant": "stream", "endpoints": [addr.to_string()],
+                "routes": [{"path": "/a", "kind": "precise"}]}]),
+            );
+            let server = &route.servant[0].state.servers[0];
+            let req = Request::get("/a").body(Body::empty()).unwrap();
+            let peer = "127.0.0.1:40000".parse().unwrap();
+            let resp = proxy(ctx, route.clone(), peer, req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(server.outstanding(), 1);
+
+            finish.send(()).unwrap();
+            let body = body::to_bytes(resp.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"abcd");
+            assert_eq!(server.outstanding(), 0);
+        });
+    }
 }
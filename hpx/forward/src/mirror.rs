@@ -0,0 +1,178 @@
+use crate::headers::HeaderRewrite;
+use crate::{forward_uri, Attempt};
+use bytes::Bytes;
+use futures::StreamExt;
+use hpx_context::ctx::Forward;
+use hpx_context::Context;
+use hpx_middleware::middleware::in_request_scope;
+use hpx_route::{request_host, Route};
+use hyper::body::HttpBody;
+use hyper::http::header::HOST;
+use hyper::http::{HeaderValue, Request};
+use hyper::Body;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Chunks of the request body buffered for the mirror before it's given up.
+const MIRROR_BUFFER: usize = 64;
+
+/// Send a copy of `req` to the mirror servant and forget about it. The body is
+/// teed as the original request streams it, a mirror falling behind by more
+/// than `MIRROR_BUFFER` chunks gets its copy aborted instead of slowing the
+/// original down. The copy counts against the mirror servant's circuit
+/// breaker and is dropped when the breaker rejects it. The request header
+/// rules of the route and of the mirror servant apply to the copy, which is
+/// given up after the mirror's `timeout`.
+pub(crate) fn mirror(
+    ctx: &Arc<Context>,
+    route: &Arc<Route>,
+    rule: usize,
+    index: usize,
+    peer: SocketAddr,
+    req: &mut Request<Body>,
+) {
+    let servant = &route.servant[index];
+    let timeout = match &route.rules[rule].path.mirror {
+        Some(mirror) => Duration::from_secs(mirror.timeout),
+        None => return,
+    };
+    let mut shadow = Request::new(Body::empty());
+    *shadow.method_mut() = req.method().clone();
+    *shadow.uri_mut() = req.uri().clone();
+    *shadow.version_mut() = req.version();
+    *shadow.headers_mut() = req.headers().clone();
+    let admission = match servant.state.admit() {
+        Some(admission) => admission,
+        None => {
+            debug!("Mirror to {} dropped, overloaded", servant.name);
+            return;
+        }
+    };
+    let slot = match servant.state.reserve_request() {
+        Some(slot) => slot,
+        None => {
+            debug!("Mirror to {} dropped, overloaded", servant.name);
+            return;
+        }
+    };
+    if !req.body().is_end_stream() {
+        *shadow.body_mut() = tee(req.body_mut());
+    }
+    let (server, in_flight) = match servant.select(&shadow, peer, &[], slot) {
+        Some(s) => s,
+        None => return,
+    };
+    *shadow.uri_mut() = match forward_uri(server, &shadow) {
+        Some(uri) => uri,
+        None => return,
+    };
+    if let Some(headers) = HeaderRewrite::new(route, rule, index, peer, req) {
+        headers.request(&mut shadow, &server.addr);
+    }
+    if let Some(host) = request_host(req).and_then(|host| shadow_host(req, host)) {
+        shadow.headers_mut().insert(HOST, host);
+    }
+    let name = servant.name.clone();
+    let attempt = Attempt::new(ctx.forward_to(shadow), &name, in_flight);
+    tokio::spawn(in_request_scope(async move {
+        let _admission = admission;
+        let exchange = async {
+            // the parts keep the copy in flight until its body is read
+            let (parts, body) = attempt.await?.into_parts();
+            hyper::body::to_bytes(body).await?;
+            Ok::<_, hyper::Error>(parts.status)
+        };
+        match tokio::time::timeout(timeout, exchange).await {
+            Ok(Ok(status)) => debug!("Mirror to {} answered {}", name, status),
+            Ok(Err(e)) => debug!("Mirror to {} error: {:?}", name, e),
+            Err(_) => debug!("Mirror to {} timed out after {:?}", name, timeout),
+        }
+    }));
+}
+
+/// The `Host` of the mirrored request, `-shadow` appended to the host name.
+fn shadow_host(req: &Request<Body>, host: &str) -> Option<HeaderValue> {
+    let authority = match req.uri().authority() {
+        Some(authority) => authority.as_str(),
+        None => req.headers().get(HOST)?.to_str().ok()?,
+    };
+    let port = &authority[authority.find(host)? + host.len()..];
+    HeaderValue::from_str(&format!("{}-shadow{}", host, port)).ok()
+}
+
+/// Replace `body` with one passing its chunks on to the returned copy.
+fn tee(body: &mut Body) -> Body {
+    let (tx, rx) = mpsc::channel::<Bytes>(MIRROR_BUFFER);
+    let aborted = Arc::new(AtomicBool::new(false));
+    let abort = aborted.clone();
+    let mut tx = Some(tx);
+    let original = std::mem::take(body).map(move |chunk| {
+        let failed = match (&chunk, tx.as_ref()) {
+            (Ok(data), Some(sender)) => sender.try_send(data.clone()).is_err(),
+            (Err(_), Some(_)) => true,
+            _ => false,
+        };
+        if failed {
+            abort.store(true, Ordering::SeqCst);
+            tx = None;
+        }
+        chunk
+    });
+    *body = Body::wrap_stream(original);
+    let copy = futures::stream::unfold(rx, move |mut rx| {
+        let aborted = aborted.clone();
+        async move {
+            match rx.recv().await {
+                Some(data) => Some((Ok(data), rx)),
+                // a copy missing chunks must not reach the mirror as complete
+                None if aborted.load(Ordering::SeqCst) => {
+                    let e = std::io::Error::other("mirror fell behind the request body");
+                    Some((Err(e), rx))
+                }
+                None => None,
+            }
+        }
+    });
+    Body::wrap_stream(copy)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy;
+    use crate::testing::{block_on, context, route, upstream};
+    use hyper::{Body, Request, StatusCode};
+    use serde_json::json;
+
+    #[test]
+    fn copies_get_the_mirror_servants_header_rules() {
+        block_on(async {
+            let ((primary, primary_head), (shadow, shadow_head)) =
+                (upstream().await, upstream().await);
+            let ctx = context().await;
+            let route = route(json!([
+                {"servant": "main", "endpoints": [primary.to_string()],
+                    "request_headers": {"set": {"x-main": "1"}},
+                    "routes": [{"path": "/a", "kind": "precise",
+                        "request_headers": {"set": {"x-servant": "{servant}"}},
+                        "mirror": {"servant": "next"}}]},
+                {"servant": "next", "endpoints": [shadow.to_string()], "routes": [],
+                    "request_headers": {"set": {"x-next": "1"}}},
+            ]));
+            let req = Request::get("http://svc/a").body(Body::empty()).unwrap();
+            let peer = "127.0.0.1:40000".parse().unwrap();
+            let resp = proxy(ctx, route, peer, req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+
+            let primary = primary_head.await.unwrap().to_ascii_lowercase();
+            assert!(primary.contains("x-main: 1") && primary.contains("x-servant: main"));
+            assert!(!primary.contains("x-next"));
+            let shadow = shadow_head.await.unwrap().to_ascii_lowercase();
+            assert!(shadow.contains("x-next: 1") && shadow.contains("x-servant: next"));
+            assert!(!shadow.contains("x-main"));
+            assert!(shadow.contains("host: svc-shadow"));
+        });
+    }
+}
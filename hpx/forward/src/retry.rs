@@ -0,0 +1,313 @@
+use crate::headers::HeaderRewrite;
+use crate::{forward_uri, Attempt};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use hpx_context::ctx::Forward;
+use hpx_context::Context;
+use hpx_error::{bad_gateway, no_healthy_upstream, overloaded};
+use hpx_route::{RetryOn, RetryPolicy, Route};
+use hyper::body::HttpBody;
+use hyper::http::header::CONTENT_LENGTH;
+use hyper::http::request::Parts;
+use hyper::http::{Request, Response, StatusCode};
+use hyper::Body;
+use rand::Rng;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+const GRPC_STATUS: &str = "grpc-status";
+const GRPC_UNAVAILABLE: &str = "14";
+
+enum Buffered {
+    /// the whole body, replayable
+    Full(Bytes),
+    /// a body over the buffer limit, only good for a single attempt
+    Partial(Body),
+}
+
+/// Forward a request by a retry policy, failed attempts are retried on
+/// another server of the servant while attempts and retry budget last.
+pub(crate) async fn forward(
+    ctx: Arc<Context>,
+    route: Arc<Route>,
+    servant: usize,
+    policy: RetryPolicy,
+    peer: SocketAddr,
+    req: Request<Body>,
+    headers: Option<Arc<HeaderRewrite>>,
+) -> Result<Response<Body>, hyper::Error> {
+    let servant = &route.servant[servant];
+    let (parts, body) = req.into_parts();
+    let body = match buffer(&parts, body, policy.buffer_limit).await? {
+        Buffered::Full(bytes) => bytes,
+        Buffered::Partial(body) => {
+            let mut req = Request::from_parts(parts, body);
+            let slot = match servant.state.reserve_request() {
+                Some(slot) => slot,
+                None => return Ok(overloaded()),
+            };
+            let (server, in_flight) = match servant.select(&req, peer, &[], slot) {
+                Some(s) => s,
+                None => return Ok(no_healthy_upstream()),
+            };
+            *req.uri_mut() = match forward_uri(server, &req) {
+                Some(uri) => uri,
+                None => return Ok(bad_gateway()),
+            };
+            if let Some(headers) = &headers {
+                headers.request(&mut req, &server.addr);
+            }
+            let name = servant.name.as_str();
+            return Attempt::new(ctx.forward_to(req), name, in_flight).await;
+        }
+    };
+    let mut tried = Vec::with_capacity(policy.max_attempts);
+    let mut _retry = None;
+    let mut slot = match servant.state.reserve_request() {
+        Some(slot) => slot,
+        None => return Ok(overloaded()),
+    };
+    loop {
+        let mut req = replay(&parts, body.clone());
+        let (server, in_flight) = match servant.select(&req, peer, &tried, slot) {
+            Some(s) => s,
+            None => return Ok(no_healthy_upstream()),
+        };
+        tried.push(in_flight.index());
+        *req.uri_mut() = match forward_uri(server, &req) {
+            Some(uri) => uri,
+            None => return Ok(bad_gateway()),
+        };
+        if let Some(headers) = &headers {
+            headers.request(&mut req, &server.addr);
+        }
+        let name = servant.name.as_str();
+        let result = Attempt::new(ctx.forward_to(req), name, in_flight).await;
+        let reason = match retry_reason(&result) {
+            Some(reason) => reason,
+            None => return result,
+        };
+        if tried.len() >= policy.max_attempts || !policy.should_retry(reason, &parts.method) {
+            return result;
+        }
+        _retry = match servant.state.begin_retry(&policy) {
+            Some(guard) => Some(guard),
+            None => {
+                debug!("Retry budget of {} exhausted", servant.name);
+                return result;
+            }
+        };
+        debug!(
+            "Retry {} on {:?}, attempt {}",
+            servant.name,
+            reason,
+            tried.len() + 1
+        );
+        tokio::time::sleep(backoff(&policy, tried.len())).await;
+        slot = match servant.state.reserve_request() {
+            Some(slot) => slot,
+            None => return result,
+        };
+    }
+}
+
+/// Read the body into memory unless it's larger than `limit`, in which case
+/// the part read so far is stitched back in front of the rest.
+async fn buffer(parts: &Parts, mut body: Body, limit: usize) -> Result<Buffered, hyper::Error> {
+    let content_length = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > limit) {
+        return Ok(Buffered::Partial(body));
+    }
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if buf.len() + chunk.len() > limit {
+            let head = futures::stream::iter(vec![Ok(buf.freeze()), Ok(chunk)]);
+            return Ok(Buffered::Partial(Body::wrap_stream(head.chain(body))));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Buffered::Full(buf.freeze()))
+}
+
+fn replay(parts: &Parts, body: Bytes) -> Request<Body> {
+    let mut req = Request::new(Body::from(body));
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+    req
+}
+
+fn retry_reason(result: &Result<Response<Body>, hyper::Error>) -> Option<RetryOn> {
+    let resp = match result {
+        Ok(resp) => resp,
+        Err(e) if e.is_connect() => return Some(RetryOn::ConnectFailure),
+        Err(_) => return Some(RetryOn::Reset),
+    };
+    match resp.status() {
+        StatusCode::BAD_GATEWAY => Some(RetryOn::BadGateway),
+        StatusCode::SERVICE_UNAVAILABLE => Some(RetryOn::ServiceUnavailable),
+        StatusCode::GATEWAY_TIMEOUT => Some(RetryOn::GatewayTimeout),
+        _ if resp
+            .headers()
+            .get(GRPC_STATUS)
+            .is_some_and(|v| v == GRPC_UNAVAILABLE) =>
+        {
+            Some(RetryOn::GrpcUnavailable)
+        }
+        _ => None,
+    }
+}
+
+/// Exponential backoff with full jitter before retry number `retry`.
+fn backoff(policy: &RetryPolicy, retry: usize) -> Duration {
+    let exp = policy
+        .base_interval
+        .saturating_mul(1 << (retry - 1).min(16))
+        .min(policy.max_interval);
+    Duration::from_millis(rand::thread_rng().gen_range(0, exp + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backoff, buffer, replay, retry_reason, Buffered};
+    use crate::testing::block_on;
+    use hpx_route::{RetryOn, RetryPolicy};
+    use hyper::body::{self, Bytes};
+    use hyper::http::{Request, Response, StatusCode};
+    use hyper::{Body, Client};
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    fn status(status: StatusCode) -> Result<Response<Body>, hyper::Error> {
+        Ok(Response::builder()
+            .status(status)
+            .body(Body::empty())
+            .unwrap())
+    }
+
+    #[test]
+    fn retry_reasons_of_responses() {
+        assert_eq!(
+            retry_reason(&status(StatusCode::BAD_GATEWAY)),
+            Some(RetryOn::BadGateway)
+        );
+        assert_eq!(
+            retry_reason(&status(StatusCode::SERVICE_UNAVAILABLE)),
+            Some(RetryOn::ServiceUnavailable)
+        );
+        assert_eq!(
+            retry_reason(&status(StatusCode::GATEWAY_TIMEOUT)),
+            Some(RetryOn::GatewayTimeout)
+        );
+        assert_eq!(retry_reason(&status(StatusCode::OK)), None);
+        assert_eq!(
+            retry_reason(&status(StatusCode::INTERNAL_SERVER_ERROR)),
+            None
+        );
+        let grpc = |code| {
+            Ok(Response::builder()
+                .header("grpc-status", code)
+                .body(Body::empty())
+                .unwrap())
+        };
+        assert_eq!(retry_reason(&grpc("14")), Some(RetryOn::GrpcUnavailable));
+        assert_eq!(retry_reason(&grpc("0")), None);
+    }
+
+    #[test]
+    fn retry_reasons_of_errors() {
+        block_on(async {
+            let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let refused = format!("http://{}/", closed.local_addr().unwrap());
+            drop(closed);
+            let result = Client::new().get(refused.parse().unwrap()).await;
+            assert_eq!(retry_reason(&result), Some(RetryOn::ConnectFailure));
+
+            // a server hanging up without answering
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let reset = format!("http://{}/", listener.local_addr().unwrap());
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                drop(stream);
+            });
+            let result = Client::new().get(reset.parse().unwrap()).await;
+            assert_eq!(retry_reason(&result), Some(RetryOn::Reset));
+        });
+    }
+
+    #[test]
+    fn backoff_is_jittered_up_to_the_max() {
+        let policy: RetryPolicy =
+            serde_json::from_value(json!({"base_interval": 10, "max_interval": 30})).unwrap();
+        for _ in 0..100 {
+            assert!(backoff(&policy, 1) <= Duration::from_millis(10));
+            assert!(backoff(&policy, 2) <= Duration::from_millis(20));
+            assert!(backoff(&policy, 3) <= Duration::from_millis(30));
+            assert!(backoff(&policy, 40) <= Duration::from_millis(30));
+        }
+    }
+
+    fn chunked(chunks: &[&'static str]) -> Body {
+        let chunks = chunks
+            .iter()
+            .map(|c| Ok::<_, std::io::Error>(Bytes::from(*c)));
+        Body::wrap_stream(futures::stream::iter(chunks.collect::<Vec<_>>()))
+    }
+
+    #[test]
+    fn small_bodies_are_buffered_for_replay() {
+        block_on(async {
+            let (parts, body) = Request::put("/a?b=1")
+                .header("x-id", "1")
+                .body(chunked(&["ab", "cd"]))
+                .unwrap()
+                .into_parts();
+            let bytes = match buffer(&parts, body, 4).await.unwrap() {
+                Buffered::Full(bytes) => bytes,
+                Buffered::Partial(_) => panic!("body within the limit"),
+            };
+            for _ in 0..2 {
+                let req = replay(&parts, bytes.clone());
+                assert_eq!(req.method(), "PUT");
+                assert_eq!(req.uri(), "/a?b=1");
+                assert_eq!(req.headers()["x-id"], "1");
+                let body = body::to_bytes(req.into_body()).await.unwrap();
+                assert_eq!(&body[..], b"abcd");
+            }
+        });
+    }
+
+    #[test]
+    fn large_bodies_are_passed_through_whole() {
+        block_on(async {
+            let (parts, body) = Request::post("/")
+                .body(chunked(&["ab", "cd", "ef"]))
+                .unwrap()
+                .into_parts();
+            let body = match buffer(&parts, body, 3).await.unwrap() {
+                Buffered::Partial(body) => body,
+                Buffered::Full(_) => panic!("body over the limit"),
+            };
+            assert_eq!(&body::to_bytes(body).await.unwrap()[..], b"abcdef");
+
+            // a declared length over the limit isn't read at all
+            let (parts, body) = Request::post("/")
+                .header("content-length", "6")
+                .body(chunked(&["abcdef"]))
+                .unwrap()
+                .into_parts();
+            assert!(matches!(
+                buffer(&parts, body, 3).await.unwrap(),
+                Buffered::Partial(_)
+            ));
+        });
+    }
+}
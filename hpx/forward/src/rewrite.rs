@@ -0,0 +1,41 @@
+use hpx_route::{PathParams, RoutePath};
+use hyper::http::header::HOST;
+use hyper::http::{HeaderValue, Request, Uri};
+use hyper::Body;
+
+/// Carries the path the client asked for on requests whose path got rewritten.
+pub const X_ORIGINAL_PATH: &str = "x-hpx-original-path";
+
+/// Apply the route's path and host rewrites to the request before its forward
+/// URI is built. An original path sent by the client is never passed on.
+pub(crate) fn rewrite(route: &RoutePath, params: &PathParams, req: &mut Request<Body>) {
+    req.headers_mut().remove(X_ORIGINAL_PATH);
+    if let Some(path) = route.rewrite_path(req.uri().path(), params) {
+        let original = req.uri().path().to_owned();
+        let path_and_query = match req.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = match path_and_query.parse() {
+            Ok(path_and_query) => Some(path_and_query),
+            Err(e) => {
+                warn!("Rewrite {} to {} error: {:?}", original, path_and_query, e);
+                return;
+            }
+        };
+        if let Ok(uri) = Uri::from_parts(parts) {
+            *req.uri_mut() = uri;
+            if let Ok(original) = HeaderValue::from_str(&original) {
+                req.headers_mut().insert(X_ORIGINAL_PATH, original);
+            }
+        }
+    }
+    if let Some(host) = route
+        .host_rewrite
+        .as_ref()
+        .and_then(|h| HeaderValue::from_str(h).ok())
+    {
+        req.headers_mut().insert(HOST, host);
+    }
+}
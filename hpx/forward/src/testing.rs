@@ -0,0 +1,72 @@
+//! Fixtures shared by the unit tests.
+use hpx_app::Config;
+use hpx_context::Context;
+use hpx_route::{Route, ServiceRoute};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+/// Run `f` to completion on a fresh runtime.
+pub(crate) fn block_on<F: Future>(f: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(f)
+}
+
+/// A context with the default config, not tracing anything.
+pub(crate) async fn context() -> Arc<Context> {
+    let conf = Config {
+        tracing_udp: None,
+        sampling_percentage: 0,
+        env_code: "test".into(),
+        connect_timeout: 1,
+        keepalive_timeout: 1,
+        request_timeout: 5,
+        idle_timeout: 0,
+        max_body_size: 0,
+        route_history: 1,
+        state_file: None,
+        route_file: None,
+        trusted_hops: 0,
+    };
+    Arc::new(Context::with_config(conf).await.unwrap())
+}
+
+/// The route of a registration payload, e.g. `[{"servant": .., ..}]`.
+pub(crate) fn route(routes: serde_json::Value) -> Arc<Route> {
+    let routes: Vec<ServiceRoute> = serde_json::from_value(routes).unwrap();
+    let ep = ServiceRoute::validate(&routes).unwrap();
+    Arc::new(Route::from_endpoints(&ep).unwrap())
+}
+
+/// Read up to the end of a response or request head.
+pub(crate) async fn read_head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0; 1];
+        assert_eq!(stream.read(&mut byte).await.unwrap(), 1);
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+/// An upstream answering a single request with an empty 200, the head of the
+/// request it got is sent on the returned channel.
+pub(crate) async fn upstream() -> (SocketAddr, oneshot::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let head = read_head(&mut stream).await;
+        let ok = "HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n";
+        stream.write_all(ok.as_bytes()).await.unwrap();
+        let _ = tx.send(head);
+    });
+    (addr, rx)
+}
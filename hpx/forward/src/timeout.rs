@@ -0,0 +1,75 @@
+use hpx_error::gateway_timeout;
+use hyper::body::HttpBody;
+use hyper::http::Response;
+use hyper::Body;
+use std::future::Future;
+use std::time::Duration;
+
+/// Marks a response hpx answered itself because the upstream timed out.
+pub(crate) struct UpstreamTimeout;
+
+/// Bound the wait for the upstream response by `timeout`, zero disables it.
+pub(crate) async fn with_timeout<F>(
+    target: &str,
+    inner: F,
+    timeout: u64,
+) -> Result<Response<Body>, hyper::Error>
+where
+    F: Future<Output = Result<Response<Body>, hyper::Error>>,
+{
+    if timeout == 0 {
+        return inner.await;
+    }
+    match tokio::time::timeout(Duration::from_secs(timeout), inner).await {
+        Ok(resp) => resp,
+        Err(_) => {
+            warn!("Forward to {} timed out after {}s", target, timeout);
+            let mut resp = gateway_timeout();
+            resp.extensions_mut().insert(UpstreamTimeout);
+            Ok(resp)
+        }
+    }
+}
+
+/// Pump the upstream body into a fresh one, aborting it when no data arrives
+/// for `idle`, if set. `held` is dropped once the body ended or the client
+/// went away. Trailers are passed on so gRPC keeps working.
+pub(crate) fn pump<H>(target: String, mut body: Body, idle: Option<Duration>, held: H) -> Body
+where
+    H: Send + 'static,
+{
+    let (mut tx, rx) = Body::channel();
+    tokio::spawn(async move {
+        let _held = held;
+        loop {
+            let next = match idle {
+                Some(idle) => match tokio::time::timeout(idle, body.data()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        warn!("Response body of {} idle for {:?}", target, idle);
+                        tx.abort();
+                        return;
+                    }
+                },
+                None => body.data().await,
+            };
+            match next {
+                Some(Ok(chunk)) => {
+                    if tx.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Err(e)) => {
+                    debug!("Response body of {} error: {:?}", target, e);
+                    tx.abort();
+                    return;
+                }
+                None => break,
+            }
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = tx.send_trailers(trailers).await;
+        }
+    });
+    rx
+}
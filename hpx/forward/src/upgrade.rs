@@ -0,0 +1,111 @@
+use hpx_context::ctx::Forward;
+use hpx_context::Context;
+use hpx_middleware::middleware::in_request_scope;
+use hyper::http::{Request, Response, StatusCode, Version};
+use hyper::Body;
+use std::sync::Arc;
+
+/// Forward the upgrade handshake to the upstream and, once both sides agree on
+/// `101 Switching Protocols`, splice the two upgraded connections together.
+pub(crate) async fn upgrade(
+    ctx: Arc<Context>,
+    target: String,
+    mut req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    let client_upgrade = hyper::upgrade::on(&mut req);
+    *req.version_mut() = Version::HTTP_11;
+    let mut resp = ctx.forward_to(req).await?;
+    if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Ok(resp);
+    }
+    let upstream_upgrade = hyper::upgrade::on(&mut resp);
+    tokio::spawn(in_request_scope(async move {
+        let mut upstream = match upstream_upgrade.await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                error!("Upgrade upstream {:?} error: {:?}", target, e);
+                return;
+            }
+        };
+        let mut client = match client_upgrade.await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                error!("Upgrade client for {:?} error: {:?}", target, e);
+                return;
+            }
+        };
+        match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+            Ok((tx, rx)) => debug!(
+                "Upgraded connection to {:?} closed, client sent {} bytes, upstream sent {} bytes",
+                target, tx, rx
+            ),
+            Err(e) => debug!("Upgraded connection to {:?} error: {:?}", target, e),
+        }
+    }));
+
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy;
+    use crate::testing::{block_on, context, read_head, route};
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn upgraded_connections_are_spliced() {
+        block_on(async {
+            // an upstream agreeing to any upgrade, then echoing what it gets
+            let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let upstream_addr = upstream.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let head = read_head(&mut stream).await.to_ascii_lowercase();
+                assert!(head.contains("upgrade: echo"));
+                let switching = "HTTP/1.1 101 Switching Protocols\r\n\
+                    connection: upgrade\r\nupgrade: echo\r\n\r\n";
+                stream.write_all(switching.as_bytes()).await.unwrap();
+                let mut buf = [0; 64];
+                loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        return;
+                    }
+                    stream.write_all(&buf[..n]).await.unwrap();
+                }
+            });
+
+            let ctx = context().await;
+            let route = route(
+                json!([{"servant": "ws", "endpoints": [upstream_addr.to_string()],
+                "routes": [{"path": "/ws", "kind": "precise"}]}]),
+            );
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (stream, peer) = listener.accept().await.unwrap();
+                let service = service_fn(move |req| proxy(ctx.clone(), route.clone(), peer, req));
+                let _ = Http::new()
+                    .serve_connection(stream, service)
+                    .with_upgrades()
+                    .await;
+            });
+
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            let handshake = "GET /ws HTTP/1.1\r\nhost: test\r\n\
+                connection: upgrade\r\nupgrade: echo\r\n\r\n";
+            client.write_all(handshake.as_bytes()).await.unwrap();
+            let head = read_head(&mut client).await;
+            assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+            assert!(head.to_ascii_lowercase().contains("upgrade: echo"));
+            client.write_all(b"ping").await.unwrap();
+            let mut echoed = [0; 4];
+            client.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(&echoed, b"ping");
+        });
+    }
+}
@@ -0,0 +1,96 @@
+//! Active health checking of the registered servers.
+
+#[macro_use]
+extern crate log;
+
+use hpx_context::ctx::{Forward, GTX};
+use hpx_route::{HealthCheck, ServantState};
+use hyper::http::{Request, Uri};
+use hyper::Body;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// How often the checker looks for servers that are due for a probe.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Probe every server of the servants that configure a `health_check` and
+/// mark them up or down in their `ServantState`. Follows route reloads, the
+/// servants of the current route are looked up again on every tick. A server
+/// whose previous probe is still running is not probed again until it ends.
+pub async fn health_check(ctx: &'static GTX) {
+    let mut next_probe: HashMap<(String, String), Instant> = HashMap::new();
+    let mut probing: HashMap<(String, String), JoinHandle<()>> = HashMap::new();
+    let mut ticker = tokio::time::interval(TICK);
+    loop {
+        ticker.tick().await;
+        let now = Instant::now();
+        let route = ctx.inner.get_route();
+        probing.retain(|_, probe| !probe.is_finished());
+        let mut scheduled = HashMap::with_capacity(next_probe.len());
+        for servant in route.servant.iter() {
+            let check = match &servant.health_check {
+                Some(check) => check,
+                None => continue,
+            };
+            for (index, server) in servant.servers.iter().enumerate() {
+                let key = (servant.name.clone(), server.addr.clone());
+                let due = next_probe.get(&key).is_none_or(|at| *at <= now);
+                if !due || probing.contains_key(&key) {
+                    let at = next_probe.get(&key).copied().unwrap_or(now);
+                    scheduled.insert(key, at);
+                    continue;
+                }
+                scheduled.insert(key.clone(), now + Duration::from_secs(check.interval));
+                let handle = tokio::spawn(probe(
+                    ctx,
+                    servant.name.clone(),
+                    server.addr.clone(),
+                    check.clone(),
+                    servant.state.clone(),
+                    index,
+                ));
+                probing.insert(key, handle);
+            }
+        }
+        next_probe = scheduled;
+    }
+}
+
+async fn probe(
+    ctx: &'static GTX,
+    servant: String,
+    addr: String,
+    check: HealthCheck,
+    state: Arc<ServantState>,
+    index: usize,
+) {
+    let passed = match Uri::from_str(format!("http://{}{}", addr, check.path).as_str()) {
+        Ok(uri) => {
+            let req = Request::get(uri).body(Body::empty()).unwrap();
+            let timeout = Duration::from_secs(check.timeout);
+            match tokio::time::timeout(timeout, ctx.inner.forward_to(req)).await {
+                Ok(Ok(resp)) => resp.status().is_success(),
+                Ok(Err(e)) => {
+                    debug!("Health check {} {} error: {:?}", servant, addr, e);
+                    false
+                }
+                Err(_) => {
+                    debug!("Health check {} {} timed out", servant, addr);
+                    false
+                }
+            }
+        }
+        Err(e) => {
+            debug!("Health check {} {} invalid uri: {:?}", servant, addr, e);
+            false
+        }
+    };
+    match state.servers[index].record_probe(passed, &check) {
+        Some(true) => info!("Health check {} {} is up", servant, addr),
+        Some(false) => warn!("Health check {} {} is down", servant, addr),
+        None => {}
+    }
+}
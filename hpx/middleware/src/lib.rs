@@ -1,5 +1,5 @@
-#[macro_use]
-pub mod middleware;
-
 #[macro_use]
 extern crate log;
+
+pub mod middleware;
+pub mod pipeline;
@@ -1,35 +1,80 @@
+use crate::pipeline::{Middleware, RequestHead};
+use async_trait::async_trait;
+use futures::StreamExt;
 use hpx_context::Context;
-use hpx_error::error::AppResponseError;
+use hpx_error::{invalid_content_length, payload_too_large};
+use hpx_route::RoutePath;
 use hpx_tracing::{set_tracing_header, Tracing, X_PARENT_ID, X_SAMPLING, X_SPAN_ID, X_TRACE_ID};
-use hyper::http::header::{HeaderName, CONTENT_TYPE};
-use hyper::http::{HeaderMap, HeaderValue, Request};
+use hyper::body::HttpBody;
+use hyper::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::http::{HeaderValue, Request, Response};
 use hyper::Body;
 use rand::Rng;
+use std::future::Future;
+use std::io;
 use std::num::{NonZeroU128, NonZeroU64};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-#[macro_export]
-macro_rules! middleware {
-    ($req:tt,$ctx:tt,$b:tt) => {
-        $b($ctx,$req)
-    };
-    ($req:tt,$ctx:tt,$a:tt,$($b:tt),*) => {
-        match $a($ctx,$req) {
-            Ok(_) => {
-                middleware!($req,$ctx,$($b),*)
-            }
-            Err(e) => {
-                Err(e)
-            }
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+/// Longest incoming request id kept, longer ones are replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    /// Id of the request the current task serves, added to its log lines.
+    pub static REQUEST_ID: String;
+}
+
+/// Keep the client's `x-request-id`, or give the request a UUIDv4 one, so it
+/// reaches the upstream and can be echoed on the response.
+pub fn with_request_id(req: &mut Request<Body>) {
+    let valid = req.headers().get(X_REQUEST_ID).is_some_and(|id| {
+        !id.is_empty()
+            && id.len() <= MAX_REQUEST_ID_LEN
+            && id.as_bytes().iter().all(|b| b.is_ascii_graphic())
+    });
+    if !valid {
+        let id = uuid::Uuid::new_v4().to_string();
+        if let Ok(id) = HeaderValue::from_str(&id) {
+            req.headers_mut().insert(X_REQUEST_ID, id);
         }
     }
 }
 
-pub const X_REQUEST_ID: &'static str = "x-request-id";
+pub fn request_id(req: &Request<Body>) -> String {
+    req.headers()
+        .get(X_REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default()
+        .to_owned()
+}
+
+/// The id of the request the current task serves, `None` outside of one.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
 
-pub fn with_trace(_: &Arc<Context>, req: &mut Request<Body>) -> Result<(), AppResponseError> {
-    set_tracing_header(parse_trace(req), is_sampling(req), req.headers_mut());
-    Ok(())
+/// Run `fut` with the current request id, for tasks spawned on its behalf.
+pub fn in_request_scope<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    let id = current_request_id().unwrap_or_default();
+    REQUEST_ID.scope(id, fut)
+}
+
+/// Propagates the trace of a request upstream, starting one if the request
+/// carries none.
+pub struct Trace;
+
+#[async_trait]
+impl Middleware for Trace {
+    async fn on_request(
+        &self,
+        _: &Arc<Context>,
+        req: &mut Request<Body>,
+    ) -> Result<(), Response<Body>> {
+        set_tracing_header(parse_trace(req), is_sampling(req), req.headers_mut());
+        Ok(())
+    }
 }
 
 pub fn parse_trace(req: &mut Request<Body>) -> Tracing {
@@ -63,42 +108,219 @@ pub fn parse_trace(req: &mut Request<Body>) -> Tracing {
     trace
 }
 
-pub fn with_print(_: &Arc<Context>, req: &mut Request<Body>) -> Result<(), AppResponseError> {
-    debug!("request incoming:{:?}", req);
-    Ok(())
+/// Logs the requests and responses passing by at debug level.
+pub struct Print;
+
+#[async_trait]
+impl Middleware for Print {
+    async fn on_request(
+        &self,
+        _: &Arc<Context>,
+        req: &mut Request<Body>,
+    ) -> Result<(), Response<Body>> {
+        debug!("request incoming:{:?}", req);
+        Ok(())
+    }
+
+    async fn on_response(&self, _: &Arc<Context>, _: &RequestHead, resp: &mut Response<Body>) {
+        debug!("response outgoing:{:?}", resp);
+    }
 }
 
-pub fn with_body_size_limit(
-    _: &Arc<Context>,
-    _: &mut Request<Body>,
-) -> Result<(), AppResponseError> {
-    Ok(())
+/// Set in the extensions of a request whose body is counted against the limit,
+/// tells the forwarder a failed upstream request was aborted for its body size.
+#[derive(Clone, Debug, Default)]
+pub struct BodyLimit(Arc<AtomicBool>);
+
+impl BodyLimit {
+    pub fn exceeded(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
 }
 
-pub fn sampling_rate_ctl(
+/// Reject requests whose body is over the `max_body_size` of the matched
+/// `rule`, or `MAX_BODY_SIZE` without one. A declared `Content-Length` is
+/// checked right away, a chunked body is counted as it streams and aborted
+/// past the limit. A `Content-Length` that isn't a number is rejected as a bad
+/// request. Returns the response rejecting the request, if any.
+pub fn with_body_size_limit(
     ctx: &Arc<Context>,
+    rule: Option<&RoutePath>,
     req: &mut Request<Body>,
-) -> Result<(), AppResponseError> {
-    if let Some(content_type) = req.headers().get(CONTENT_TYPE) {
-        if content_type.eq("application/grpc") {
-            return Ok(());
-        }
+) -> Option<Response<Body>> {
+    let limit = rule
+        .and_then(|rule| rule.max_body_size)
+        .unwrap_or(ctx.get_config().max_body_size);
+    if limit == 0 || req.body().is_end_stream() {
+        return None;
     }
-    if let None = req.headers().get(X_TRACE_ID) {
-        let state = ctx.get_state();
-        let c = rand::thread_rng().gen_range(0..100);
-        if state.should_sampling(c as usize) {
-            req.headers_mut()
-                .insert(X_SAMPLING, HeaderValue::from_static("true"));
-        }
+    if let Some(len) = req.headers().get(CONTENT_LENGTH) {
+        return match len
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+        {
+            Some(len) if len > limit => Some(payload_too_large()),
+            Some(_) => None,
+            None => Some(invalid_content_length()),
+        };
     }
+    let flag = BodyLimit::default();
+    let exceeded = flag.0.clone();
+    let mut seen = 0;
+    let body = std::mem::take(req.body_mut()).map(move |chunk| {
+        let chunk = chunk?;
+        seen += chunk.len();
+        if seen > limit {
+            exceeded.store(true, Ordering::SeqCst);
+            let e = io::Error::new(io::ErrorKind::InvalidData, "request body too large");
+            return Err(Box::new(e) as Box<dyn std::error::Error + Send + Sync>);
+        }
+        Ok(chunk)
+    });
+    *req.body_mut() = Body::wrap_stream(body);
+    req.extensions_mut().insert(flag);
+    None
+}
 
-    Ok(())
+/// Marks a share of the requests starting a trace for sampling, by
+/// `SAMPLING_PERCENTAGE`. gRPC requests are never sampled.
+pub struct Sampling;
+
+#[async_trait]
+impl Middleware for Sampling {
+    async fn on_request(
+        &self,
+        ctx: &Arc<Context>,
+        req: &mut Request<Body>,
+    ) -> Result<(), Response<Body>> {
+        if let Some(content_type) = req.headers().get(CONTENT_TYPE) {
+            if content_type.eq("application/grpc") {
+                return Ok(());
+            }
+        }
+        if req.headers().get(X_TRACE_ID).is_none() {
+            let state = ctx.get_state();
+            let c = rand::thread_rng().gen_range(0..100);
+            if state.should_sampling(c as usize) {
+                req.headers_mut()
+                    .insert(X_SAMPLING, HeaderValue::from_static("true"));
+            }
+        }
+        Ok(())
+    }
 }
 
 pub fn is_sampling(req: &mut Request<Body>) -> bool {
-    if let Some(_) = req.headers().get(X_SAMPLING) {
+    if req.headers().get(X_SAMPLING).is_some() {
         return true;
     }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{with_body_size_limit, BodyLimit};
+    use hpx_app::Config;
+    use hpx_context::Context;
+    use hpx_route::RoutePath;
+    use hyper::body::{self, Bytes};
+    use hyper::http::{Request, Response, StatusCode};
+    use hyper::Body;
+    use serde_json::json;
+    use std::future::Future;
+    use std::sync::Arc;
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    async fn context(max_body_size: usize) -> Arc<Context> {
+        let conf = Config {
+            tracing_udp: None,
+            sampling_percentage: 0,
+            env_code: "test".into(),
+            connect_timeout: 1,
+            keepalive_timeout: 1,
+            request_timeout: 0,
+            idle_timeout: 0,
+            max_body_size,
+            route_history: 1,
+            state_file: None,
+            route_file: None,
+            trusted_hops: 0,
+        };
+        Arc::new(Context::with_config(conf).await.unwrap())
+    }
+
+    fn chunked(chunks: &[&'static str]) -> Body {
+        let chunks = chunks
+            .iter()
+            .map(|c| Ok::<_, std::io::Error>(Bytes::from(*c)));
+        Body::wrap_stream(futures::stream::iter(chunks.collect::<Vec<_>>()))
+    }
+
+    async fn rejected(resp: Response<Body>) -> (StatusCode, String) {
+        assert_eq!(
+            resp.headers()["content-type"],
+            "application/json; charset=UTF-8"
+        );
+        let status = resp.status();
+        let body = body::to_bytes(resp.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn declared_lengths_are_checked_up_front() {
+        block_on(async {
+            let ctx = context(4).await;
+            let req = |len: &str| {
+                Request::post("/")
+                    .header("content-length", len)
+                    .body(Body::from("abcde"))
+                    .unwrap()
+            };
+            assert!(with_body_size_limit(&ctx, None, &mut req("4")).is_none());
+
+            let resp = with_body_size_limit(&ctx, None, &mut req("5")).unwrap();
+            let (status, body) = rejected(resp).await;
+            assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+            assert_eq!(body, r#"{"code":413,"message":"request body too large"}"#);
+
+            let resp = with_body_size_limit(&ctx, None, &mut req("5x")).unwrap();
+            let (status, body) = rejected(resp).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body, r#"{"code":400,"message":"invalid content-length"}"#);
+
+            // the rule's limit wins over the global one
+            let rule: RoutePath =
+                serde_json::from_value(json!({"path": "/", "kind": "precise", "max_body_size": 8}))
+                    .unwrap();
+            assert!(with_body_size_limit(&ctx, Some(&rule), &mut req("5")).is_none());
+        });
+    }
+
+    #[test]
+    fn streamed_bodies_are_counted() {
+        block_on(async {
+            let ctx = context(4).await;
+            let mut req = Request::post("/").body(chunked(&["ab", "cd"])).unwrap();
+            assert!(with_body_size_limit(&ctx, None, &mut req).is_none());
+            let limit = req.extensions().get::<BodyLimit>().cloned().unwrap();
+            let body = body::to_bytes(req.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"abcd");
+            assert!(!limit.exceeded());
+
+            let mut req = Request::post("/")
+                .body(chunked(&["ab", "cd", "e"]))
+                .unwrap();
+            assert!(with_body_size_limit(&ctx, None, &mut req).is_none());
+            let limit = req.extensions().get::<BodyLimit>().cloned().unwrap();
+            assert!(body::to_bytes(req.into_body()).await.is_err());
+            assert!(limit.exceeded());
+        });
+    }
+}
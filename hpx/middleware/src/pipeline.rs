@@ -0,0 +1,424 @@
+use crate::middleware::{Print, Sampling, Trace};
+use async_trait::async_trait;
+use hpx_context::Context;
+use hpx_error::error::AppResponseError;
+use hpx_route::{EndpointsMap, MiddlewareSpec, Route, RouteKind, RouteRule, ValidationError};
+use hyper::http::{HeaderMap, Method, Request, Response, StatusCode, Uri, Version};
+use hyper::Body;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, LazyLock, RwLock, Weak};
+
+/// Middlewares of routes without a chain of their own.
+pub const DEFAULT_CHAIN: &[&str] = &["print", "sampling", "trace"];
+
+/// A step of the request pipeline. `on_request` hooks run in chain order
+/// before the request is forwarded, `on_response` hooks in reverse order on
+/// the way back.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    /// Inspect or change the request, returning a response answers the
+    /// request with it instead of forwarding it.
+    async fn on_request(
+        &self,
+        _ctx: &Arc<Context>,
+        _req: &mut Request<Body>,
+    ) -> Result<(), Response<Body>> {
+        Ok(())
+    }
+
+    /// Inspect or change the response to the request `req` was the head of.
+    async fn on_response(
+        &self,
+        _ctx: &Arc<Context>,
+        _req: &RequestHead,
+        _resp: &mut Response<Body>,
+    ) {
+    }
+}
+
+/// The request as the last `on_request` hook left it, for response hooks.
+#[derive(Debug, Clone)]
+pub struct RequestHead {
+    pub method: Method,
+    pub uri: Uri,
+    pub version: Version,
+    pub headers: HeaderMap,
+}
+
+impl RequestHead {
+    fn of(req: &Request<Body>) -> Self {
+        Self {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            version: req.version(),
+            headers: req.headers().clone(),
+        }
+    }
+}
+
+/// Builds a middleware from the options of a route's `MiddlewareSpec`.
+pub type Factory = fn(&Map<String, Value>) -> Result<Arc<dyn Middleware>, String>;
+
+static FACTORIES: LazyLock<RwLock<HashMap<String, Factory>>> = LazyLock::new(|| {
+    let mut factories: HashMap<String, Factory> = HashMap::new();
+    factories.insert("print".into(), |_| Ok(Arc::new(Print)));
+    factories.insert("sampling".into(), |_| Ok(Arc::new(Sampling)));
+    factories.insert("trace".into(), |_| Ok(Arc::new(Trace)));
+    RwLock::new(factories)
+});
+
+/// Make a middleware available to route chains under `name`, replacing any
+/// registered before.
+pub fn register(name: &str, factory: Factory) {
+    FACTORIES.write().unwrap().insert(name.to_owned(), factory);
+}
+
+fn build(spec: &MiddlewareSpec) -> Result<Arc<dyn Middleware>, String> {
+    let factory = FACTORIES.read().unwrap().get(&spec.name).copied();
+    match factory {
+        Some(factory) => factory(&spec.options),
+        None => Err(format!("{} is not a registered middleware", spec.name)),
+    }
+}
+
+/// Check the middleware chains of the registered routes can be built.
+pub fn validate(ep: &EndpointsMap) -> Result<(), ValidationError> {
+    let mut errors = Vec::new();
+    let mut names = ep.keys().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        for (i, route) in ep[name].routes.iter().enumerate() {
+            for (j, spec) in route.middlewares.iter().flatten().enumerate() {
+                if let Err(e) = build(spec) {
+                    let field = format!("{}.routes[{}].middlewares[{}]", name, i, j);
+                    errors.push(AppResponseError::invalid_field(field, &e));
+                }
+            }
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(ValidationError(errors)),
+    }
+}
+
+/// A chain of middlewares around forwarding a request.
+#[derive(Clone, Default)]
+pub struct Pipeline(Arc<[Arc<dyn Middleware>]>);
+
+impl Pipeline {
+    fn new(specs: &[MiddlewareSpec]) -> Self {
+        let chain = specs
+            .iter()
+            .map(|spec| match build(spec) {
+                Ok(middleware) => middleware,
+                Err(e) => {
+                    error!("Middleware {} unavailable: {}", spec.name, e);
+                    Arc::new(Unavailable)
+                }
+            })
+            .collect::<Vec<_>>();
+        Pipeline(chain.into())
+    }
+
+    /// Run the request hooks, `next` for the response unless a hook answered
+    /// already, then the response hooks of the middlewares the request
+    /// passed through.
+    pub async fn run<F, Fut>(
+        &self,
+        ctx: &Arc<Context>,
+        mut req: Request<Body>,
+        next: F,
+    ) -> Result<Response<Body>, hyper::Error>
+    where
+        F: FnOnce(Request<Body>) -> Fut,
+        Fut: Future<Output = Result<Response<Body>, hyper::Error>>,
+    {
+        for (i, middleware) in self.0.iter().enumerate() {
+            if let Err(mut resp) = middleware.on_request(ctx, &mut req).await {
+                let head = RequestHead::of(&req);
+                for middleware in self.0[..i].iter().rev() {
+                    middleware.on_response(ctx, &head, &mut resp).await;
+                }
+                return Ok(resp);
+            }
+        }
+        let head = RequestHead::of(&req);
+        let mut resp = next(req).await?;
+        for middleware in self.0.iter().rev() {
+            middleware.on_response(ctx, &head, &mut resp).await;
+        }
+        Ok(resp)
+    }
+}
+
+/// A rule by what tells it apart from the other rules: its servant, path,
+/// hosts and conditions.
+type RuleKey = (String, RouteKind, String, Vec<String>, String);
+
+/// Pipelines built for the rules of the last route asked for. The chains
+/// outlive the route while the rules keep their middlewares, so an update of
+/// the routes doesn't reset the state of unchanged middlewares.
+struct Pipelines {
+    route: Weak<Route>,
+    default: Pipeline,
+    rules: Vec<Pipeline>,
+    chains: HashMap<RuleKey, (Vec<MiddlewareSpec>, Pipeline)>,
+}
+
+static PIPELINES: LazyLock<RwLock<Option<Arc<Pipelines>>>> = LazyLock::new(Default::default);
+
+/// The pipeline of the route rule at `rule`, the default chain when there is
+/// no rule or it has no chain.
+pub fn pipeline(route: &Arc<Route>, rule: Option<usize>) -> Pipeline {
+    let pipelines = pipelines(route);
+    rule.and_then(|rule| pipelines.rules.get(rule))
+        .unwrap_or(&pipelines.default)
+        .clone()
+}
+
+fn pipelines(route: &Arc<Route>) -> Arc<Pipelines> {
+    // the weak reference keeps the route's address from being reused
+    let built_for = |p: &Arc<Pipelines>| p.route.as_ptr() == Arc::as_ptr(route);
+    if let Some(pipelines) = PIPELINES.read().unwrap().as_ref().filter(|p| built_for(p)) {
+        return pipelines.clone();
+    }
+    let mut current = PIPELINES.write().unwrap();
+    match current.as_ref() {
+        Some(pipelines) if built_for(pipelines) => pipelines.clone(),
+        prev => {
+            let pipelines = Arc::new(Pipelines::new(route, prev.map(|p| &**p)));
+            *current = Some(pipelines.clone());
+            pipelines
+        }
+    }
+}
+
+impl Pipelines {
+    fn new(route: &Arc<Route>, prev: Option<&Pipelines>) -> Self {
+        let default = match prev {
+            Some(prev) => prev.default.clone(),
+            None => {
+                let specs = DEFAULT_CHAIN
+                    .iter()
+                    .map(|name| MiddlewareSpec {
+                        name: name.to_string(),
+                        options: Map::new(),
+                    })
+                    .collect::<Vec<_>>();
+                Pipeline::new(&specs)
+            }
+        };
+        let mut chains = HashMap::new();
+        let rules = route
+            .rules
+            .iter()
+            .map(|rule| {
+                let specs = match &rule.path.middlewares {
+                    Some(specs) => specs,
+                    None => return default.clone(),
+                };
+                let key = rule_key(route, rule);
+                let pipeline = match prev.and_then(|p| p.chains.get(&key)) {
+                    Some((built, pipeline)) if built == specs => pipeline.clone(),
+                    _ => Pipeline::new(specs),
+                };
+                chains.insert(key, (specs.clone(), pipeline.clone()));
+                pipeline
+            })
+            .collect();
+        Self {
+            route: Arc::downgrade(route),
+            default,
+            rules,
+            chains,
+        }
+    }
+}
+
+fn rule_key(route: &Route, rule: &RouteRule) -> RuleKey {
+    let path = &rule.path;
+    let conditions =
+        serde_json::to_string(&(&path.methods, &path.headers, &path.query)).unwrap_or_default();
+    (
+        route.servant[rule.servant].name.clone(),
+        path.kind,
+        path.path.clone(),
+        path.hosts.clone(),
+        conditions,
+    )
+}
+
+/// Stands in for a middleware that couldn't be built, the route's requests
+/// are refused rather than skipping it.
+struct Unavailable;
+
+#[async_trait]
+impl Middleware for Unavailable {
+    async fn on_request(
+        &self,
+        _: &Arc<Context>,
+        _: &mut Request<Body>,
+    ) -> Result<(), Response<Body>> {
+        let mut resp = Response::new(Body::from("middleware unavailable"));
+        *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        Err(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hpx_app::Config;
+    use hpx_route::ServiceRoute;
+    use serde_json::json;
+    use std::sync::Mutex;
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Logs its hooks, answers the request itself when `answer` is set.
+    struct Step {
+        name: &'static str,
+        answer: bool,
+        log: Log,
+    }
+
+    #[async_trait]
+    impl Middleware for Step {
+        async fn on_request(
+            &self,
+            _: &Arc<Context>,
+            req: &mut Request<Body>,
+        ) -> Result<(), Response<Body>> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} request", self.name));
+            req.headers_mut()
+                .insert("x-step", self.name.parse().unwrap());
+            match self.answer {
+                true => Err(Response::new(Body::empty())),
+                false => Ok(()),
+            }
+        }
+
+        async fn on_response(&self, _: &Arc<Context>, req: &RequestHead, _: &mut Response<Body>) {
+            let seen = req.headers["x-step"].to_str().unwrap();
+            let line = format!("{} response after {}", self.name, seen);
+            self.log.lock().unwrap().push(line);
+        }
+    }
+
+    fn pipeline(steps: &[(&'static str, bool)], log: &Log) -> Pipeline {
+        let chain = steps
+            .iter()
+            .map(|(name, answer)| {
+                Arc::new(Step {
+                    name,
+                    answer: *answer,
+                    log: log.clone(),
+                }) as Arc<dyn Middleware>
+            })
+            .collect::<Vec<_>>();
+        Pipeline(chain.into())
+    }
+
+    fn run(pipeline: &Pipeline, log: &Log) {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let conf = Config {
+                tracing_udp: None,
+                sampling_percentage: 0,
+                env_code: "test".into(),
+                connect_timeout: 1,
+                keepalive_timeout: 1,
+                request_timeout: 0,
+                idle_timeout: 0,
+                max_body_size: 0,
+                route_history: 1,
+                state_file: None,
+                route_file: None,
+                trusted_hops: 0,
+            };
+            let ctx = Arc::new(Context::with_config(conf).await.unwrap());
+            let next_log = log.clone();
+            let next = move |_| async move {
+                next_log.lock().unwrap().push("forward".into());
+                Ok(Response::new(Body::empty()))
+            };
+            pipeline
+                .run(&ctx, Request::new(Body::empty()), next)
+                .await
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn responses_unwind_in_reverse_order() {
+        let log = Log::default();
+        run(&pipeline(&[("a", false), ("b", false)], &log), &log);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "a request",
+                "b request",
+                "forward",
+                "b response after b",
+                "a response after b"
+            ]
+        );
+    }
+
+    #[test]
+    fn an_answering_middleware_stops_the_request() {
+        let log = Log::default();
+        run(
+            &pipeline(&[("a", false), ("b", true), ("c", false)], &log),
+            &log,
+        );
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["a request", "b request", "a response after b"]
+        );
+    }
+
+    fn chained(options: Value) -> Arc<Route> {
+        let routes = json!([
+            {"servant": "a", "endpoints": ["10.0.0.1:80"], "routes": [
+                {"path": "/a", "kind": "precise", "middlewares": [{"name": "print"}]},
+                {"path": "/b", "kind": "precise",
+                    "middlewares": [{"name": "print", "options": options}]},
+            ]},
+        ]);
+        let routes: Vec<ServiceRoute> = serde_json::from_value(routes).unwrap();
+        let ep = ServiceRoute::validate(&routes).unwrap();
+        Arc::new(Route::from_endpoints(&ep).unwrap())
+    }
+
+    /// One test, the pipelines are shared by every route of the process.
+    #[test]
+    fn pipelines_are_built_once_and_kept_while_unchanged() {
+        let route = Arc::new(Route::default());
+        let (first, second) = (
+            super::pipeline(&route, None),
+            super::pipeline(&route, Some(3)),
+        );
+        assert!(Arc::ptr_eq(&first.0, &second.0));
+        assert_eq!(first.0.len(), DEFAULT_CHAIN.len());
+
+        let route = chained(json!(1));
+        let a = super::pipeline(&route, Some(0));
+        let b = super::pipeline(&route, Some(1));
+        assert!(!Arc::ptr_eq(&a.0, &b.0));
+        assert!(Arc::ptr_eq(&b.0, &super::pipeline(&route, Some(1)).0));
+
+        // only the chain whose options changed is built again
+        let updated = chained(json!(2));
+        assert!(Arc::ptr_eq(&a.0, &super::pipeline(&updated, Some(0)).0));
+        assert!(!Arc::ptr_eq(&b.0, &super::pipeline(&updated, Some(1)).0));
+    }
+}
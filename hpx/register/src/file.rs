@@ -0,0 +1,180 @@
+use hpx_context::ctx::{Forward, GTX};
+use hpx_middleware::pipeline::validate as validate_middlewares;
+use hpx_route::ServiceRoute;
+use notify::{RecursiveMode, Watcher};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Changes within this window are reloaded once, editors write in several steps.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Load the routes of `ROUTE_FILE` and reload them whenever the file changes.
+/// A file that fails to parse is logged and the current routes are kept.
+pub async fn watch_route_file(ctx: &'static GTX) {
+    let path = match &ctx.inner.get_config().route_file {
+        Some(path) => Path::new(path).to_path_buf(),
+        None => return,
+    };
+    let mut loaded = None;
+    reload(ctx, &path, &mut loaded);
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = match notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    }) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!("Watch route file {:?} error: {:?}", path, e);
+            return;
+        }
+    };
+    // watch the directory, editors and config maps replace the file rather
+    // than write to it, which drops a watch on the file itself. A config map
+    // swaps a `..data` symlink the file points through, so any change in the
+    // directory is checked against the content last loaded.
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+        error!("Watch route file {:?} error: {:?}", path, e);
+        return;
+    }
+    while let Some(event) = rx.recv().await {
+        let changed = match event {
+            Ok(event) => !event.kind.is_access(),
+            Err(e) => {
+                warn!("Watch route file {:?} error: {:?}", path, e);
+                false
+            }
+        };
+        if !changed {
+            continue;
+        }
+        tokio::time::sleep(DEBOUNCE).await;
+        while rx.try_recv().is_ok() {}
+        reload(ctx, &path, &mut loaded);
+    }
+}
+
+/// Reload the file unless its content hashes to `loaded`, the hash of the
+/// content last read.
+fn reload(ctx: &'static GTX, path: &Path, loaded: &mut Option<u64>) {
+    let content = match std::fs::read(path) {
+        Ok(content) => content,
+        Err(e) => {
+            error!("Load route file {:?} error: {}, routes kept", path, e);
+            return;
+        }
+    };
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    let hash = hasher.finish();
+    if loaded.replace(hash) == Some(hash) {
+        debug!("Route file {:?} unchanged", path);
+        return;
+    }
+    let routes = match parse(path, &content) {
+        Ok(routes) => routes,
+        Err(e) => {
+            error!("Load route file {:?} error: {}, routes kept", path, e);
+            return;
+        }
+    };
+    let rmap = match ServiceRoute::validate(&routes).and_then(|rmap| {
+        validate_middlewares(&rmap)?;
+        Ok(rmap)
+    }) {
+        Ok(rmap) => rmap,
+        Err(e) => {
+            error!("Invalid route file {:?}: {}, routes kept", path, e);
+            return;
+        }
+    };
+    match ctx.inner.update_route(&|route| route.update(&rmap)) {
+        Ok(version) => info!(
+            "Load route file {:?} succeed, route version {}",
+            path, version
+        ),
+        Err(e) => error!("Load route file {:?} error: {}, routes kept", path, e),
+    }
+}
+
+/// Parse the file as YAML when named `.yaml` or `.yml`, JSON otherwise.
+fn parse(path: &Path, content: &[u8]) -> Result<Vec<ServiceRoute>, Box<dyn std::error::Error>> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("yaml") | Some("yml") => Ok(serde_yaml::from_slice(content)?),
+        _ => Ok(serde_json::from_slice(content)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, reload};
+    use crate::testing::{block_on, context};
+    use hpx_context::ctx::Forward;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    const YAML: &str = "
+- servant: a
+  endpoints: [10.0.0.1:80]
+  routes:
+    - path: /a
+      kind: precise
+";
+
+    const JSON: &str = r#"[{"servant": "a", "endpoints": ["10.0.0.2:80"],
+        "routes": [{"path": "/a", "kind": "precise"}]}]"#;
+
+    #[test]
+    fn parses_by_extension() {
+        for path in ["routes.yaml", "routes.yml"] {
+            let routes = parse(Path::new(path), YAML.as_bytes()).unwrap();
+            assert_eq!(routes[0].servant, "a");
+            assert_eq!(routes[0].endpoint.routes[0].path, "/a");
+        }
+        let routes = parse(Path::new("routes.json"), JSON.as_bytes()).unwrap();
+        assert_eq!(routes[0].servant, "a");
+        // anything but YAML is read as JSON
+        assert!(parse(Path::new("routes"), JSON.as_bytes()).is_ok());
+        assert!(parse(Path::new("routes.json"), YAML.as_bytes()).is_err());
+    }
+
+    /// A route file path in a fresh directory of its own.
+    fn route_file(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hpx-route-file-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    #[test]
+    fn unchanged_content_is_not_reloaded() {
+        block_on(async {
+            let ctx = context(10).await;
+            let path = route_file("routes.yaml");
+            let mut loaded = None;
+            fs::write(&path, YAML).unwrap();
+            reload(ctx, &path, &mut loaded);
+            assert_eq!(ctx.inner.get_route().version, 1);
+            // rewritten with the same content, as a config map swap does
+            fs::write(&path, YAML).unwrap();
+            reload(ctx, &path, &mut loaded);
+            assert_eq!(ctx.inner.get_route().version, 1);
+
+            fs::write(&path, YAML.replace("10.0.0.1", "10.0.0.3")).unwrap();
+            reload(ctx, &path, &mut loaded);
+            let route = ctx.inner.get_route();
+            assert_eq!(route.version, 2);
+            assert_eq!(route.servant[0].servers[0].addr, "10.0.0.3:80");
+
+            // broken content keeps the routes
+            fs::write(&path, "- servant: [").unwrap();
+            reload(ctx, &path, &mut loaded);
+            assert_eq!(ctx.inner.get_route().version, 2);
+        });
+    }
+}
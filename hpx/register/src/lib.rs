@@ -2,30 +2,39 @@
 extern crate log;
 
 use crate::unix::SocketIncoming;
+use crate::version::{check_version, etag, if_match, VersionConflict};
 use hpx_context::ctx::{Forward, GTX};
-use hpx_error::{bad_request, not_found, status_ok};
-use hpx_route::{Route, RouteEndpoint, RoutePath};
+use hpx_error::{bad_request, invalid_fields, not_found, precondition_failed, status_ok};
+use hpx_middleware::pipeline::validate as validate_middlewares;
+use hpx_route::{Endpoint, Route, RouteEndpoint, ServiceRoute, SplitTarget, ValidationError};
 use hyper::body::Buf;
-use hyper::http::header::CONTENT_TYPE;
+use hyper::http::header::{CONTENT_TYPE, ETAG};
 use hyper::http::{Method, Request, Response, StatusCode};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Body;
 use hyper::Server;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::io::ErrorKind;
 use std::path::Path;
 use tokio::net::UnixListener;
 
+mod file;
+#[cfg(test)]
+mod testing;
 mod unix;
+mod version;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ServiceRoute {
-    #[serde(rename = "servant")]
-    pub servant: String,
-    #[serde(rename = "routes")]
-    pub routes: Vec<RoutePath>,
-    #[serde(rename = "endpoints")]
-    pub endpoints: Vec<String>,
+pub use file::watch_route_file;
+
+/// New split weights for the routes of a servant on `path`.
+#[derive(Deserialize, Debug)]
+struct SplitUpdate {
+    #[serde(rename = "path")]
+    path: String,
+    #[serde(rename = "split")]
+    split: Vec<SplitTarget>,
 }
 
 pub async fn register_server(ctx: &'static GTX, uds: &str) -> std::io::Result<()> {
@@ -49,40 +58,430 @@ pub async fn serve_http(
     ctx: &'static GTX,
     req: Request<Body>,
 ) -> Result<Response<Body>, hyper::Error> {
-    match (req.method(), req.uri().path()) {
-        (&Method::POST, "/route/register") => {
-            let body = hyper::body::aggregate(req.into_body()).await?;
-            let service_routes: Vec<ServiceRoute> = match serde_json::from_reader(body.reader()) {
+    let path = req.uri().path().to_owned();
+    let segments = path.trim_matches('/').split('/').collect::<Vec<&str>>();
+    let expected = match if_match(&req) {
+        Ok(expected) => expected,
+        Err(e) => return Ok(bad_request(e)),
+    };
+    let dry_run = req
+        .uri()
+        .query()
+        .is_some_and(|q| q.split('&').any(|pair| pair == "dry_run=true"));
+    match (req.method().clone(), segments.as_slice()) {
+        (Method::POST, ["route", "register"]) => {
+            let service_routes: Vec<ServiceRoute> = match parse_body(req).await? {
+                Ok(s) => s,
+                Err(e) => return Ok(bad_request(e.to_string())),
+            };
+            let rmap = match ServiceRoute::validate(&service_routes) {
+                Ok(rmap) => rmap,
+                Err(e) => return Ok(invalid_fields(&e.0)),
+            };
+            let rst = apply(ctx, dry_run, &|route| {
+                check_version(route, expected)?;
+                route.update(&rmap)
+            });
+            Ok(updated(rst, "Reload hpx routes"))
+        }
+        (Method::PUT, ["servants", name]) => {
+            let endpoint: RouteEndpoint = match parse_body(req).await? {
+                Ok(s) => s,
+                Err(e) => return Ok(bad_request(e.to_string())),
+            };
+            let rst = apply(ctx, dry_run, &|route| {
+                check_version(route, expected)?;
+                let mut ep = route.endpoints.clone();
+                ep.insert(name.to_string(), endpoint.clone());
+                route.update(&ep)
+            });
+            Ok(updated(rst, &format!("Put servant {}", name)))
+        }
+        (Method::DELETE, ["servants", name]) => {
+            let rst = apply(ctx, dry_run, &|route| {
+                check_version(route, expected)?;
+                let mut ep = route.endpoints.clone();
+                ep.remove(*name).ok_or_else(|| servant_not_found(name))?;
+                route.update(&ep)
+            });
+            Ok(updated(rst, &format!("Delete servant {}", name)))
+        }
+        (Method::POST, ["servants", name, "endpoints"]) => {
+            let endpoints: Vec<Endpoint> = match parse_body(req).await? {
                 Ok(s) => s,
                 Err(e) => return Ok(bad_request(e.to_string())),
             };
-            let mut rmap = HashMap::with_capacity(service_routes.len());
-            service_routes.iter().for_each(|r| {
-                rmap.insert(
-                    r.servant.clone(),
-                    RouteEndpoint {
-                        routes: r.routes.clone(),
-                        endpoints: r.endpoints.clone(),
-                    },
-                );
+            let rst = apply(ctx, dry_run, &|route| {
+                check_version(route, expected)?;
+                let mut ep = route.endpoints.clone();
+                let servant = ep.get_mut(*name).ok_or_else(|| servant_not_found(name))?;
+                for endpoint in endpoints.iter() {
+                    // an endpoint already registered gets its weight updated
+                    servant.endpoints.retain(|e| e.addr() != endpoint.addr());
+                    servant.endpoints.push(endpoint.clone());
+                }
+                route.update(&ep)
             });
-            let route = Route::from_endpoints(&rmap).unwrap();
-            ctx.inner.reload_route(route);
-            info!("Reload hpx routes succeed");
-            Ok(status_ok())
+            Ok(updated(rst, &format!("Add endpoints of servant {}", name)))
         }
-        (&Method::GET, "/routes") => {
+        (Method::DELETE, ["servants", name, "endpoints", addr]) => {
+            let rst = apply(ctx, dry_run, &|route| {
+                check_version(route, expected)?;
+                let mut ep = route.endpoints.clone();
+                let servant = ep.get_mut(*name).ok_or_else(|| servant_not_found(name))?;
+                let len = servant.endpoints.len();
+                servant.endpoints.retain(|e| e.addr() != *addr);
+                if servant.endpoints.len() == len {
+                    return Err(std::io::Error::new(
+                        ErrorKind::NotFound,
+                        format!("endpoint {} of servant {} not found", addr, name),
+                    ));
+                }
+                route.update(&ep)
+            });
+            Ok(updated(
+                rst,
+                &format!("Delete endpoint {} of servant {}", addr, name),
+            ))
+        }
+        (Method::PUT, ["servants", name, "split"]) => {
+            let update: SplitUpdate = match parse_body(req).await? {
+                Ok(s) => s,
+                Err(e) => return Ok(bad_request(e.to_string())),
+            };
+            let rst = apply(ctx, dry_run, &|route| {
+                check_version(route, expected)?;
+                let mut ep = route.endpoints.clone();
+                let servant = ep.get_mut(*name).ok_or_else(|| servant_not_found(name))?;
+                let mut found = false;
+                for r in servant.routes.iter_mut().filter(|r| r.path == update.path) {
+                    r.split = update.split.clone();
+                    found = true;
+                }
+                if !found {
+                    return Err(std::io::Error::new(
+                        ErrorKind::NotFound,
+                        format!("route {} of servant {} not found", update.path, name),
+                    ));
+                }
+                route.update(&ep)
+            });
+            Ok(updated(
+                rst,
+                &format!("Split route {} of servant {}", update.path, name),
+            ))
+        }
+        (Method::GET, ["routes"]) => {
             let route = ctx.inner.get_route();
             let mut rst = HashMap::new();
             rst.insert("servant", route.servant.clone());
-            let b = serde_json::to_string(&rst).unwrap_or(String::from("NULL"));
-            let resp = Response::builder()
-                .status(StatusCode::OK)
-                .header(CONTENT_TYPE, "application/json; charset=UTF-8")
-                .body(Body::from(b))
-                .unwrap();
+            let mut resp = json_ok(&rst);
+            resp.headers_mut().insert(ETAG, etag(route.version));
             Ok(resp)
         }
+        (Method::GET, ["routes", "versions"]) => {
+            let mut rst = HashMap::new();
+            rst.insert("current", serde_json::json!(ctx.inner.get_route().version));
+            rst.insert("versions", serde_json::json!(ctx.inner.route_history()));
+            Ok(json_ok(&rst))
+        }
+        (Method::GET, ["routes", "versions", from, "diff", to]) => {
+            let history = ctx.inner.route_history();
+            let find = |v: &str| history.iter().find(|s| v.parse() == Ok(s.version));
+            match (find(from), find(to)) {
+                (Some(from), Some(to)) => Ok(json_ok(&from.diff(to))),
+                _ => Ok(not_found()),
+            }
+        }
+        (Method::POST, ["routes", "versions", version, "rollback"]) => {
+            let snapshot = match ctx
+                .inner
+                .route_history()
+                .into_iter()
+                .find(|s| version.parse() == Ok(s.version))
+            {
+                Some(snapshot) => snapshot,
+                None => return Ok(not_found()),
+            };
+            let rst = apply(ctx, dry_run, &|route| {
+                check_version(route, expected)?;
+                route.update(&snapshot.endpoints)
+            });
+            Ok(updated(
+                rst,
+                &format!("Roll back routes to version {}", version),
+            ))
+        }
         _ => Ok(not_found()),
     }
 }
+
+async fn parse_body<T: DeserializeOwned>(
+    req: Request<Body>,
+) -> Result<serde_json::Result<T>, hyper::Error> {
+    let body = hyper::body::aggregate(req.into_body()).await?;
+    Ok(serde_json::from_reader(body.reader()))
+}
+
+/// Install the route built by `update`, with `dry_run` only check that it
+/// builds and leave the current route in place.
+fn apply(
+    ctx: &'static GTX,
+    dry_run: bool,
+    update: &dyn Fn(&Route) -> std::io::Result<Route>,
+) -> std::io::Result<u64> {
+    // middleware chains can only be checked against the middlewares registered
+    let update = |route: &Route| {
+        let route = update(route)?;
+        validate_middlewares(&route.endpoints).map_err(std::io::Error::other)?;
+        Ok(route)
+    };
+    if !dry_run {
+        return ctx.inner.update_route(&update);
+    }
+    let route = ctx.inner.get_route();
+    update(&route)?;
+    info!("Dry run on route version {} passed", route.version);
+    Ok(route.version)
+}
+
+fn servant_not_found(name: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::NotFound, format!("servant {} not found", name))
+}
+
+fn updated(rst: std::io::Result<u64>, action: &str) -> Response<Body> {
+    match rst {
+        Ok(version) => {
+            info!("{} succeed, route version {}", action, version);
+            let mut resp = status_ok();
+            resp.headers_mut().insert(ETAG, etag(version));
+            resp
+        }
+        Err(e) => {
+            warn!("{} failed: {}", action, e);
+            if let Some(invalid) = ValidationError::of(&e) {
+                return invalid_fields(&invalid.0);
+            }
+            match e.kind() {
+                ErrorKind::NotFound => not_found(),
+                _ if VersionConflict::is(&e) => precondition_failed(e.to_string()),
+                _ => bad_request(e.to_string()),
+            }
+        }
+    }
+}
+
+fn json_ok<T: Serialize>(body: &T) -> Response<Body> {
+    let b = serde_json::to_string(body).unwrap_or(String::from("NULL"));
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json; charset=UTF-8")
+        .body(Body::from(b))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{block_on, call, context};
+    use hpx_context::ctx::{Forward, GTX};
+    use hyper::http::{Method, StatusCode};
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    fn servant(endpoints: &[&str]) -> Value {
+        json!({"endpoints": endpoints, "routes": [{"path": "/a", "kind": "precise"}]})
+    }
+
+    #[test]
+    fn servants_are_put_and_deleted() {
+        block_on(async {
+            let ctx = context(10).await;
+            let put = call(
+                ctx,
+                Method::PUT,
+                "/servants/a",
+                servant(&["10.0.0.1:80"]),
+                None,
+            )
+            .await;
+            assert_eq!(put.status, StatusCode::OK);
+            assert_eq!(put.headers["etag"], "\"1\"");
+            let routes = call(ctx, Method::GET, "/routes", Value::Null, None).await;
+            let routes: Value = serde_json::from_str(&routes.body).unwrap();
+            assert_eq!(routes["servant"][0]["name"], "a");
+
+            let delete = call(ctx, Method::DELETE, "/servants/a", Value::Null, None).await;
+            assert_eq!(delete.status, StatusCode::OK);
+            assert!(ctx.inner.get_route().servant.is_empty());
+            let again = call(ctx, Method::DELETE, "/servants/a", Value::Null, None).await;
+            assert_eq!(again.status, StatusCode::NOT_FOUND);
+            assert_eq!(ctx.inner.get_route().version, 2);
+        });
+    }
+
+    #[test]
+    fn endpoints_are_added_and_removed() {
+        block_on(async {
+            let ctx = context(10).await;
+            call(
+                ctx,
+                Method::PUT,
+                "/servants/a",
+                servant(&["10.0.0.1:80"]),
+                None,
+            )
+            .await;
+            let uri = "/servants/a/endpoints";
+            let add = call(ctx, Method::POST, uri, json!(["10.0.0.2:80"]), None).await;
+            assert_eq!(add.status, StatusCode::OK);
+            let addrs = |ctx: &'static GTX| {
+                let route = ctx.inner.get_route();
+                let servers = route.servant[0].servers.iter();
+                servers.map(|s| s.addr.clone()).collect::<Vec<_>>()
+            };
+            assert_eq!(addrs(ctx), vec!["10.0.0.1:80", "10.0.0.2:80"]);
+
+            let uri = "/servants/a/endpoints/10.0.0.1:80";
+            let delete = call(ctx, Method::DELETE, uri, Value::Null, None).await;
+            assert_eq!(delete.status, StatusCode::OK);
+            assert_eq!(addrs(ctx), vec!["10.0.0.2:80"]);
+            let again = call(ctx, Method::DELETE, uri, Value::Null, None).await;
+            assert_eq!(again.status, StatusCode::NOT_FOUND);
+        });
+    }
+
+    #[test]
+    fn unknown_servants_are_not_found() {
+        block_on(async {
+            let ctx = context(10).await;
+            let uri = "/servants/b/endpoints";
+            let add = call(ctx, Method::POST, uri, json!(["10.0.0.2:80"]), None).await;
+            assert_eq!(add.status, StatusCode::NOT_FOUND);
+            let uri = "/servants/b/endpoints/10.0.0.2:80";
+            let delete = call(ctx, Method::DELETE, uri, Value::Null, None).await;
+            assert_eq!(delete.status, StatusCode::NOT_FOUND);
+            let delete = call(ctx, Method::DELETE, "/servants/b", Value::Null, None).await;
+            assert_eq!(delete.status, StatusCode::NOT_FOUND);
+            assert_eq!(ctx.inner.get_route().version, 0);
+        });
+    }
+
+    #[test]
+    fn unchanged_endpoints_keep_their_counters() {
+        block_on(async {
+            let ctx = context(10).await;
+            let two = servant(&["10.0.0.1:80", "10.0.0.2:80"]);
+            call(ctx, Method::PUT, "/servants/a", two, None).await;
+            let before = ctx.inner.get_route();
+
+            // a new route on the same endpoints keeps the servant's state
+            let mut routes = servant(&["10.0.0.1:80", "10.0.0.2:80"]);
+            routes["routes"]
+                .as_array_mut()
+                .unwrap()
+                .push(json!({"path": "/b", "kind": "precise"}));
+            call(ctx, Method::PUT, "/servants/a", routes, None).await;
+            let after = ctx.inner.get_route();
+            assert!(Arc::ptr_eq(
+                &before.servant[0].state,
+                &after.servant[0].state
+            ));
+
+            // another endpoint keeps the state of the ones still there
+            let uri = "/servants/a/endpoints";
+            call(ctx, Method::POST, uri, json!(["10.0.0.3:80"]), None).await;
+            let added = ctx.inner.get_route();
+            let (before, added) = (&before.servant[0].state, &added.servant[0].state);
+            assert!(!Arc::ptr_eq(before, added));
+            assert!(Arc::ptr_eq(&before.servers[0], &added.servers[0]));
+            assert!(Arc::ptr_eq(&before.servers[1], &added.servers[1]));
+        });
+    }
+
+    #[test]
+    fn stale_versions_are_refused() {
+        block_on(async {
+            let ctx = context(10).await;
+            let put = |body, version| call(ctx, Method::PUT, "/servants/a", body, version);
+            assert_eq!(
+                put(servant(&["10.0.0.1:80"]), Some("0")).await.status,
+                StatusCode::OK
+            );
+            let stale = put(servant(&["10.0.0.2:80"]), Some("\"0\"")).await;
+            assert_eq!(stale.status, StatusCode::PRECONDITION_FAILED);
+            assert_eq!(stale.body, "route version is 1, not 0");
+            assert_eq!(ctx.inner.get_route().version, 1);
+            let current = put(servant(&["10.0.0.2:80"]), Some("\"1\"")).await;
+            assert_eq!(current.status, StatusCode::OK);
+            assert_eq!(current.headers["etag"], "\"2\"");
+            let invalid = put(servant(&["10.0.0.3:80"]), Some("v2")).await;
+            assert_eq!(invalid.status, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn versions_are_diffed() {
+        block_on(async {
+            let ctx = context(10).await;
+            let (a, b) = (
+                servant(&["10.0.0.1:80"]),
+                json!({"endpoints": ["10.0.0.2:80"], "routes": []}),
+            );
+            for (uri, body) in [("/servants/a", a), ("/servants/b", b)] {
+                let put = call(ctx, Method::PUT, uri, body, None).await;
+                assert_eq!(put.status, StatusCode::OK, "{}", put.body);
+            }
+            let uri = "/servants/a/endpoints";
+            call(ctx, Method::POST, uri, json!(["10.0.0.3:80"]), None).await;
+
+            let diff = call(
+                ctx,
+                Method::GET,
+                "/routes/versions/1/diff/3",
+                Value::Null,
+                None,
+            )
+            .await;
+            assert_eq!(diff.status, StatusCode::OK);
+            let diff: Value = serde_json::from_str(&diff.body).unwrap();
+            assert_eq!(diff["added"]["b"]["endpoints"], json!(["10.0.0.2:80"]));
+            assert_eq!(diff["removed"], json!({}));
+            let changed = &diff["changed"]["a"];
+            assert_eq!(changed["from"]["endpoints"], json!(["10.0.0.1:80"]));
+            assert_eq!(
+                changed["to"]["endpoints"],
+                json!(["10.0.0.1:80", "10.0.0.3:80"])
+            );
+
+            let unknown = call(
+                ctx,
+                Method::GET,
+                "/routes/versions/1/diff/9",
+                Value::Null,
+                None,
+            );
+            assert_eq!(unknown.await.status, StatusCode::NOT_FOUND);
+        });
+    }
+
+    #[test]
+    fn rollbacks_only_reach_kept_versions() {
+        block_on(async {
+            let ctx = context(2).await;
+            for addr in ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"] {
+                call(ctx, Method::PUT, "/servants/a", servant(&[addr]), None).await;
+            }
+            let uri = "/routes/versions/1/rollback";
+            let pruned = call(ctx, Method::POST, uri, Value::Null, None).await;
+            assert_eq!(pruned.status, StatusCode::NOT_FOUND);
+            assert_eq!(ctx.inner.get_route().version, 3);
+
+            let uri = "/routes/versions/2/rollback";
+            let kept = call(ctx, Method::POST, uri, Value::Null, None).await;
+            assert_eq!(kept.status, StatusCode::OK);
+            let route = ctx.inner.get_route();
+            assert_eq!(route.version, 4);
+            assert_eq!(route.servant[0].servers[0].addr, "10.0.0.2:80");
+        });
+    }
+}
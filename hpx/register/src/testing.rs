@@ -0,0 +1,70 @@
+//! Fixtures shared by the unit tests.
+use crate::serve_http;
+use hpx_app::Config;
+use hpx_context::ctx::GTX;
+use hpx_context::Context;
+use hyper::http::{HeaderMap, Method, Request, StatusCode};
+use hyper::Body;
+use std::future::Future;
+use std::sync::Arc;
+
+/// Run `f` to completion on a fresh runtime.
+pub(crate) fn block_on<F: Future>(f: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(f)
+}
+
+/// A context of its own for a test, keeping the last `route_history` routes.
+pub(crate) async fn context(route_history: usize) -> &'static GTX {
+    let conf = Config {
+        tracing_udp: None,
+        sampling_percentage: 0,
+        env_code: "test".into(),
+        connect_timeout: 1,
+        keepalive_timeout: 1,
+        request_timeout: 0,
+        idle_timeout: 0,
+        max_body_size: 0,
+        route_history,
+        state_file: None,
+        route_file: None,
+        trusted_hops: 0,
+    };
+    let inner = Arc::new(Context::with_config(conf).await.unwrap());
+    Box::leak(Box::new(GTX { inner }))
+}
+
+/// An admin API response, its body as a string.
+pub(crate) struct Answer {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+/// Send a request to the admin API, `body` as JSON unless it's null.
+pub(crate) async fn call(
+    ctx: &'static GTX,
+    method: Method,
+    uri: &str,
+    body: serde_json::Value,
+    if_match: Option<&str>,
+) -> Answer {
+    let mut req = Request::builder().method(method).uri(uri);
+    if let Some(version) = if_match {
+        req = req.header("if-match", version);
+    }
+    let body = match body.is_null() {
+        true => Body::empty(),
+        false => Body::from(body.to_string()),
+    };
+    let resp = serve_http(ctx, req.body(body).unwrap()).await.unwrap();
+    let (parts, body) = resp.into_parts();
+    let body = hyper::body::to_bytes(body).await.unwrap();
+    Answer {
+        status: parts.status,
+        headers: parts.headers,
+        body: String::from_utf8(body.to_vec()).unwrap(),
+    }
+}
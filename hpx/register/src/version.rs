@@ -0,0 +1,62 @@
+use hpx_route::Route;
+use hyper::http::header::IF_MATCH;
+use hyper::http::{HeaderValue, Request};
+use hyper::Body;
+use std::fmt;
+use std::io::Error;
+
+/// The route changed since the version a registration was based on.
+#[derive(Debug)]
+pub(crate) struct VersionConflict {
+    expected: u64,
+    current: u64,
+}
+
+impl fmt::Display for VersionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "route version is {}, not {}",
+            self.current, self.expected
+        )
+    }
+}
+
+impl std::error::Error for VersionConflict {}
+
+impl VersionConflict {
+    pub(crate) fn is(e: &Error) -> bool {
+        e.get_ref().is_some_and(|e| e.is::<VersionConflict>())
+    }
+}
+
+/// The version of an `If-Match` header, either `3` or `"3"`.
+pub(crate) fn if_match(req: &Request<Body>) -> Result<Option<u64>, String> {
+    let value = match req.headers().get(IF_MATCH) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    value
+        .to_str()
+        .ok()
+        .map(|v| v.trim().trim_matches('"'))
+        .and_then(|v| v.parse().ok())
+        .map(Some)
+        .ok_or_else(|| format!("Invalid If-Match version {:?}", value))
+}
+
+/// The `ETag` of a route version, as accepted back by `If-Match`.
+pub(crate) fn etag(version: u64) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{}\"", version)).unwrap()
+}
+
+/// Fail with a `VersionConflict` unless the route is at the expected version.
+pub(crate) fn check_version(route: &Route, expected: Option<u64>) -> Result<(), Error> {
+    match expected {
+        Some(expected) if expected != route.version => Err(Error::other(VersionConflict {
+            expected,
+            current: route.version,
+        })),
+        _ => Ok(()),
+    }
+}
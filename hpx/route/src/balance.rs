@@ -0,0 +1,327 @@
+use crate::{Maglev, RequestSlot, RingHash, Servant, ServantState, Server};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Smoothing factor of the latency EWMA, higher values forget faster.
+const EWMA_ALPHA: f64 = 0.3;
+
+/// Picks the server of a servant that should take the next request.
+///
+/// Implementations keep no state of their own beyond what is computed once at
+/// construction, the per-request counters live in `ServantState`. `hash` is
+/// the request's hash key, only consistent-hash balancers look at it, and
+/// only servers whose `available` flag is set may be picked.
+pub trait LoadBalancer: Send + Sync + Debug {
+    fn pick(&self, servant: &Servant, hash: Option<u64>, available: &[bool]) -> Option<usize>;
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LbPolicy {
+    #[default]
+    RoundRobin,
+    Random,
+    LeastRequest,
+    PowerOfTwo,
+    Ewma,
+    RingHash,
+    Maglev,
+}
+
+impl LbPolicy {
+    pub fn build(&self, servers: &[Server]) -> Box<dyn LoadBalancer> {
+        match self {
+            LbPolicy::RoundRobin => Box::new(RoundRobin),
+            LbPolicy::Random => Box::new(Random),
+            LbPolicy::LeastRequest => Box::new(LeastRequest),
+            LbPolicy::PowerOfTwo => Box::new(PowerOfTwo),
+            LbPolicy::Ewma => Box::new(Ewma),
+            LbPolicy::RingHash => Box::new(RingHash::new(servers)),
+            LbPolicy::Maglev => Box::new(Maglev::new(servers)),
+        }
+    }
+}
+
+/// Round-robin, smooth weighted (nginx-style) when any server carries a weight.
+#[derive(Debug)]
+pub struct RoundRobin;
+
+impl LoadBalancer for RoundRobin {
+    fn pick(&self, servant: &Servant, _: Option<u64>, available: &[bool]) -> Option<usize> {
+        let servers = &servant.servers;
+        if servers.iter().all(|s| s.weight.is_none()) {
+            let count = servant.state.count.fetch_add(1, Ordering::SeqCst);
+            return (0..servers.len())
+                .map(|i| (count + i) % servers.len())
+                .find(|&i| available[i]);
+        }
+        let mut current = servant.state.current_weights.lock().unwrap();
+        let (mut total, mut best) = (0, None);
+        for (i, server) in servers.iter().enumerate().filter(|(i, _)| available[*i]) {
+            let weight = server.effective_weight();
+            current[i] += weight;
+            total += weight;
+            if best.is_none_or(|b: usize| current[i] > current[b]) {
+                best = Some(i);
+            }
+        }
+        // servers weighted 0 or drained take no traffic, even when they're all that's left
+        if total <= 0 {
+            return None;
+        }
+        current[best?] -= total;
+        best
+    }
+}
+
+#[derive(Debug)]
+pub struct Random;
+
+impl LoadBalancer for Random {
+    fn pick(&self, _: &Servant, _: Option<u64>, available: &[bool]) -> Option<usize> {
+        let candidates = candidates(available);
+        if candidates.is_empty() {
+            return None;
+        }
+        Some(candidates[rand::thread_rng().gen_range(0..candidates.len())])
+    }
+}
+
+/// Fewest outstanding requests wins, ties are broken round-robin so an idle
+/// servant doesn't pile everything on its first server.
+#[derive(Debug)]
+pub struct LeastRequest;
+
+impl LoadBalancer for LeastRequest {
+    fn pick(&self, servant: &Servant, _: Option<u64>, available: &[bool]) -> Option<usize> {
+        let len = servant.servers.len();
+        let offset = servant.state.count.fetch_add(1, Ordering::SeqCst);
+        (0..len)
+            .map(|i| (offset + i) % len)
+            .filter(|&i| available[i])
+            .min_by_key(|&i| servant.state.servers[i].outstanding())
+    }
+}
+
+/// Power of two choices: the less loaded of two random servers.
+#[derive(Debug)]
+pub struct PowerOfTwo;
+
+impl LoadBalancer for PowerOfTwo {
+    fn pick(&self, servant: &Servant, _: Option<u64>, available: &[bool]) -> Option<usize> {
+        let state = &servant.state;
+        two_choices(&candidates(available), |i| {
+            state.servers[i].outstanding() as f64
+        })
+    }
+}
+
+/// Power of two choices weighed by latency EWMA times outstanding requests.
+#[derive(Debug)]
+pub struct Ewma;
+
+impl LoadBalancer for Ewma {
+    fn pick(&self, servant: &Servant, _: Option<u64>, available: &[bool]) -> Option<usize> {
+        let state = &servant.state;
+        two_choices(&candidates(available), |i| state.servers[i].cost())
+    }
+}
+
+fn candidates(available: &[bool]) -> Vec<usize> {
+    (0..available.len()).filter(|&i| available[i]).collect()
+}
+
+fn two_choices<F: Fn(usize) -> f64>(candidates: &[usize], cost: F) -> Option<usize> {
+    match candidates.len() {
+        0 => None,
+        1 => Some(candidates[0]),
+        len => {
+            let mut rng = rand::thread_rng();
+            let a = rng.gen_range(0..len);
+            let b = (a + rng.gen_range(1..len)) % len;
+            let (a, b) = (candidates[a], candidates[b]);
+            if cost(b) < cost(a) {
+                Some(b)
+            } else {
+                Some(a)
+            }
+        }
+    }
+}
+
+/// Load and health of a single server, indexed like `Servant.servers`.
+#[derive(Serialize, Debug)]
+pub struct ServerState {
+    pub addr: String,
+    outstanding: AtomicUsize,
+    /// latency EWMA in microseconds
+    #[serde(rename = "latency_us")]
+    ewma: AtomicU64,
+    pub(crate) healthy: AtomicBool,
+    /// consecutive passed and failed health probes
+    #[serde(skip)]
+    pub(crate) passes: AtomicUsize,
+    #[serde(skip)]
+    pub(crate) fails: AtomicUsize,
+    /// consecutive failed requests seen by outlier detection
+    #[serde(skip)]
+    pub(crate) errors: AtomicUsize,
+    pub(crate) ejections: AtomicUsize,
+    /// end of the current ejection, milliseconds since the unix epoch
+    #[serde(rename = "ejected_until_ms")]
+    pub(crate) ejected_until: AtomicU64,
+}
+
+impl ServerState {
+    pub fn new(addr: &str) -> Self {
+        Self {
+            addr: addr.to_owned(),
+            outstanding: AtomicUsize::new(0),
+            ewma: AtomicU64::new(0),
+            healthy: AtomicBool::new(true),
+            passes: AtomicUsize::new(0),
+            fails: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+            ejections: AtomicUsize::new(0),
+            ejected_until: AtomicU64::new(0),
+        }
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    pub fn latency(&self) -> Duration {
+        Duration::from_micros(self.ewma.load(Ordering::Relaxed))
+    }
+
+    fn cost(&self) -> f64 {
+        // unprobed servers cost 1µs so they get tried first
+        let ewma = self.ewma.load(Ordering::Relaxed).max(1) as f64;
+        ewma * (self.outstanding() + 1) as f64
+    }
+
+    fn observe(&self, latency: Duration) {
+        let sample = latency.as_micros() as u64;
+        let _ = self
+            .ewma
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |prev| {
+                Some(match prev {
+                    0 => sample,
+                    _ => (prev as f64 * (1.0 - EWMA_ALPHA) + sample as f64 * EWMA_ALPHA) as u64,
+                })
+            });
+    }
+}
+
+/// A request in flight to one server, counted as outstanding until dropped.
+#[derive(Debug)]
+pub struct InFlight {
+    state: Arc<ServantState>,
+    index: usize,
+    start: Instant,
+    _slot: RequestSlot,
+}
+
+impl InFlight {
+    pub fn new(state: Arc<ServantState>, index: usize, slot: RequestSlot) -> Self {
+        state.servers[index]
+            .outstanding
+            .fetch_add(1, Ordering::Relaxed);
+        Self {
+            state,
+            index,
+            start: Instant::now(),
+            _slot: slot,
+        }
+    }
+
+    pub fn addr(&self) -> &str {
+        self.state.servers[self.index].addr.as_str()
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Report the outcome of the request to outlier detection, returns the
+    /// ejection time when it got the server ejected.
+    pub fn record(&self, failed: bool) -> Option<Duration> {
+        self.state.record_outcome(self.index, failed)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let server = &self.state.servers[self.index];
+        server.outstanding.fetch_sub(1, Ordering::Relaxed);
+        server.observe(self.start.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::servant;
+    use serde_json::json;
+
+    fn weighted(weights: &[i8]) -> Servant {
+        let endpoints = weights
+            .iter()
+            .enumerate()
+            .map(|(i, w)| json!({"addr": format!("10.0.0.{}:80", i), "weight": w}))
+            .collect::<Vec<_>>();
+        servant(json!({"routes": [], "endpoints": endpoints}))
+    }
+
+    fn picks(servant: &Servant, available: &[bool], n: usize) -> Vec<Option<usize>> {
+        (0..n)
+            .map(|_| RoundRobin.pick(servant, None, available))
+            .collect()
+    }
+
+    #[test]
+    fn smooth_weighted_round_robin_interleaves() {
+        let servant = weighted(&[5, 1, 1]);
+        let order = picks(&servant, &[true; 3], 7);
+        let expected = [0, 0, 1, 0, 2, 0, 0].iter().map(|&i| Some(i));
+        assert_eq!(order, expected.collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn weights_hold_over_many_rounds() {
+        let servant = weighted(&[3, 2, 0]);
+        let mut counts = [0; 3];
+        picks(&servant, &[true; 3], 500)
+            .into_iter()
+            .for_each(|i| counts[i.unwrap()] += 1);
+        assert_eq!(counts, [300, 200, 0]);
+    }
+
+    #[test]
+    fn zero_and_drained_weights_pick_nothing() {
+        assert_eq!(picks(&weighted(&[0, 0]), &[true; 2], 3), vec![None; 3]);
+        assert_eq!(picks(&weighted(&[0, -1]), &[true; 2], 1), vec![None]);
+        // the only weighted server being unavailable leaves nothing either
+        assert_eq!(picks(&weighted(&[0, 4]), &[true, false], 1), vec![None]);
+    }
+
+    #[test]
+    fn unavailable_servers_are_skipped() {
+        let servant = weighted(&[1, 1, 1]);
+        let order = picks(&servant, &[true, false, true], 4);
+        assert!(order.iter().all(|i| *i != Some(1)));
+        assert_eq!(picks(&servant, &[false; 3], 1), vec![None]);
+    }
+
+    #[test]
+    fn unweighted_round_robin_rotates() {
+        let servant = servant(json!({"routes": [], "endpoints": ["a:1", "b:1", "c:1"]}));
+        let order = picks(&servant, &[true; 3], 4);
+        assert_eq!(order, vec![Some(0), Some(1), Some(2), Some(0)]);
+    }
+}
@@ -0,0 +1,120 @@
+use crate::ServantState;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Limits beyond which requests to a servant are rejected right away.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CircuitBreaker {
+    /// attempts on the wire to the servant's servers
+    #[serde(rename = "max_requests", default = "default_max_requests")]
+    pub max_requests: usize,
+    /// requests accepted for the servant and still waiting for a response,
+    /// retries and backoff included
+    #[serde(rename = "max_pending", default = "default_max_pending")]
+    pub max_pending: usize,
+    /// retries in flight
+    #[serde(rename = "max_retries", default = "default_max_retries")]
+    pub max_retries: usize,
+}
+
+fn default_max_requests() -> usize {
+    1024
+}
+
+fn default_max_pending() -> usize {
+    1024
+}
+
+fn default_max_retries() -> usize {
+    3
+}
+
+impl ServantState {
+    /// Accept a request for the servant, `None` when `max_pending` is hit.
+    pub fn admit(self: &Arc<Self>) -> Option<Admission> {
+        let max = self.breaker.as_ref().map_or(usize::MAX, |b| b.max_pending);
+        self.pending
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pending| {
+                if pending < max {
+                    Some(pending + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()?;
+        Some(Admission {
+            state: self.clone(),
+        })
+    }
+
+    /// Count another attempt on the wire, `None` when it would exceed
+    /// `max_requests`.
+    pub fn reserve_request(self: &Arc<Self>) -> Option<RequestSlot> {
+        let requests = self.requests.fetch_add(1, Ordering::SeqCst) + 1;
+        let slot = RequestSlot {
+            state: self.clone(),
+        };
+        match &self.breaker {
+            // dropping the slot takes the attempt back
+            Some(breaker) if requests > breaker.max_requests => None,
+            _ => Some(slot),
+        }
+    }
+}
+
+/// A request accepted for a servant, pending until dropped.
+#[derive(Debug)]
+pub struct Admission {
+    state: Arc<ServantState>,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.state.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// An attempt counted against `max_requests` until dropped.
+#[derive(Debug)]
+pub struct RequestSlot {
+    state: Arc<ServantState>,
+}
+
+impl Drop for RequestSlot {
+    fn drop(&mut self) {
+        self.state.requests.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::servant;
+    use serde_json::json;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn requests_are_reserved_up_to_the_limit() {
+        let state = servant(json!({"routes": [], "endpoints": ["10.0.0.1:80"],
+            "circuit_breaker": {"max_requests": 2}}))
+        .state;
+        let first = state.reserve_request().unwrap();
+        let _second = state.reserve_request().unwrap();
+        assert!(state.reserve_request().is_none());
+        assert_eq!(state.requests.load(Ordering::SeqCst), 2);
+        drop(first);
+        assert!(state.reserve_request().is_some());
+        assert_eq!(state.requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn pending_requests_are_admitted_up_to_the_limit() {
+        let state = servant(json!({"routes": [], "endpoints": ["10.0.0.1:80"],
+            "circuit_breaker": {"max_pending": 1}}))
+        .state;
+        let admission = state.admit().unwrap();
+        assert!(state.admit().is_none());
+        drop(admission);
+        assert!(state.admit().is_some());
+    }
+}
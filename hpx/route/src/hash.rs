@@ -0,0 +1,249 @@
+use crate::{LoadBalancer, Random, RoundRobin, Servant, Server};
+use hyper::http::header::COOKIE;
+use hyper::http::Request;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+
+/// Virtual nodes per unit of weight on the hash ring.
+const RING_VNODES: usize = 100;
+/// Size of the Maglev lookup table, must be prime.
+const MAGLEV_TABLE_SIZE: usize = 65537;
+
+/// What a consistent-hash balancer hashes to pick a server.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    Header(String),
+    Cookie(String),
+    Query(String),
+    SourceIp,
+}
+
+impl HashKey {
+    /// Hash of the request key, `None` when the request doesn't carry it.
+    pub fn hash<B>(&self, req: &Request<B>, peer: SocketAddr) -> Option<u64> {
+        match self {
+            HashKey::Header(name) => req
+                .headers()
+                .get(name.as_str())
+                .map(|v| hash(&v.as_bytes())),
+            HashKey::Cookie(name) => req
+                .headers()
+                .get_all(COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(';'))
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| hash(&v)),
+            HashKey::Query(name) => req
+                .uri()
+                .query()?
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| hash(&v)),
+            HashKey::SourceIp => Some(hash(&peer.ip())),
+        }
+    }
+}
+
+pub(crate) fn hash<T: Hash + ?Sized>(t: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    t.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Ketama style hash ring, servers are placed by address so only the keys of
+/// added or removed servers move when the endpoint list changes.
+#[derive(Debug)]
+pub struct RingHash {
+    ring: Vec<(u64, usize)>,
+}
+
+impl RingHash {
+    pub fn new(servers: &[Server]) -> Self {
+        let mut ring = Vec::new();
+        for (index, server) in servers.iter().enumerate() {
+            let vnodes = RING_VNODES * server.effective_weight() as usize;
+            for vnode in 0..vnodes {
+                ring.push((hash(&(server.addr.as_str(), vnode)), index));
+            }
+        }
+        ring.sort_unstable();
+        Self { ring }
+    }
+}
+
+impl LoadBalancer for RingHash {
+    fn pick(&self, servant: &Servant, hash: Option<u64>, available: &[bool]) -> Option<usize> {
+        let hash = match hash {
+            Some(hash) => hash,
+            None => return Random.pick(servant, None, available),
+        };
+        // walk clockwise past unavailable servers, their keys spill over to
+        // the next server on the ring only
+        let pos = self.ring.partition_point(|(h, _)| *h < hash);
+        self.ring[pos..]
+            .iter()
+            .chain(self.ring[..pos].iter())
+            .map(|(_, index)| *index)
+            .find(|&index| available[index])
+    }
+}
+
+/// Maglev consistent hashing, a fixed size lookup table filled from each
+/// server's own permutation of the slots. Servers claim slots in proportion to
+/// their weight, those weighted 0 or drained get none.
+#[derive(Debug)]
+pub struct Maglev {
+    table: Vec<u32>,
+}
+
+impl Maglev {
+    pub fn new(servers: &[Server]) -> Self {
+        let weights = servers
+            .iter()
+            .map(|s| s.effective_weight())
+            .collect::<Vec<_>>();
+        let max_weight = weights.iter().copied().max().unwrap_or(0);
+        if max_weight <= 0 {
+            return Self { table: vec![] };
+        }
+        let size = MAGLEV_TABLE_SIZE;
+        let mut table = vec![u32::MAX; size];
+        let permutations = servers
+            .iter()
+            .map(|s| {
+                let offset = hash(&(s.addr.as_str(), 0)) as usize % size;
+                let skip = hash(&(s.addr.as_str(), 1)) as usize % (size - 1) + 1;
+                (offset, skip)
+            })
+            .collect::<Vec<_>>();
+        let mut next = vec![0; servers.len()];
+        // a server claims a slot each time its credit reaches the top weight
+        let mut credit = vec![0; servers.len()];
+        let mut filled = 0;
+        'fill: loop {
+            for (index, (offset, skip)) in permutations.iter().enumerate() {
+                credit[index] += weights[index];
+                if credit[index] < max_weight {
+                    continue;
+                }
+                credit[index] -= max_weight;
+                let mut slot = (offset + next[index] * skip) % size;
+                while table[slot] != u32::MAX {
+                    next[index] += 1;
+                    slot = (offset + next[index] * skip) % size;
+                }
+                table[slot] = index as u32;
+                next[index] += 1;
+                filled += 1;
+                if filled == size {
+                    break 'fill;
+                }
+            }
+        }
+        Self { table }
+    }
+}
+
+impl LoadBalancer for Maglev {
+    fn pick(&self, servant: &Servant, hash: Option<u64>, available: &[bool]) -> Option<usize> {
+        let hash = match hash {
+            Some(hash) => hash,
+            None => return Random.pick(servant, None, available),
+        };
+        let len = self.table.len();
+        (0..len)
+            .map(|i| self.table[(hash as usize).wrapping_add(i) % len] as usize)
+            .find(|&index| available[index])
+            // no weighted server of the table is available
+            .or_else(|| RoundRobin.pick(servant, None, available))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::servant;
+    use serde_json::json;
+
+    fn servers(weights: &[Option<i8>]) -> Vec<Server> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, weight)| Server {
+                addr: format!("10.0.0.{}:80", i),
+                weight: *weight,
+            })
+            .collect()
+    }
+
+    fn unweighted(n: usize) -> Servant {
+        let endpoints = (0..n)
+            .map(|i| format!("10.0.0.{}:80", i))
+            .collect::<Vec<_>>();
+        servant(json!({"routes": [], "endpoints": endpoints}))
+    }
+
+    #[test]
+    fn ring_hash_only_moves_keys_of_removed_servers() {
+        let servant = unweighted(3);
+        let (before, after) = (
+            RingHash::new(&servers(&[None; 3])),
+            RingHash::new(&servers(&[None; 2])),
+        );
+        for key in 0..1000u64 {
+            let key = hash(&key);
+            let was = before.pick(&servant, Some(key), &[true; 3]).unwrap();
+            let now = after.pick(&servant, Some(key), &[true; 2]).unwrap();
+            if was != 2 {
+                assert_eq!(was, now);
+            }
+        }
+    }
+
+    #[test]
+    fn ring_hash_skips_unavailable_servers() {
+        let (servant, ring) = (unweighted(3), RingHash::new(&servers(&[None; 3])));
+        for key in 0..100u64 {
+            let pick = ring.pick(&servant, Some(hash(&key)), &[true, false, true]);
+            assert_ne!(pick, Some(1));
+        }
+        assert_eq!(ring.pick(&servant, Some(1), &[false; 3]), None);
+    }
+
+    #[test]
+    fn maglev_slots_follow_weights() {
+        let maglev = Maglev::new(&servers(&[Some(3), Some(1), Some(0)]));
+        let mut counts = [0usize; 3];
+        maglev.table.iter().for_each(|&i| counts[i as usize] += 1);
+        assert_eq!(counts.iter().sum::<usize>(), MAGLEV_TABLE_SIZE);
+        assert_eq!(counts[2], 0);
+        let share = counts[0] as f64 / MAGLEV_TABLE_SIZE as f64;
+        assert!((share - 0.75).abs() < 0.01, "share {}", share);
+    }
+
+    #[test]
+    fn maglev_is_consistent() {
+        let (servant, maglev) = (unweighted(3), Maglev::new(&servers(&[None; 3])));
+        for key in 0..100u64 {
+            let first = maglev.pick(&servant, Some(hash(&key)), &[true; 3]);
+            assert!(first.is_some());
+            assert_eq!(first, maglev.pick(&servant, Some(hash(&key)), &[true; 3]));
+        }
+    }
+
+    #[test]
+    fn maglev_falls_back_when_the_table_has_no_available_server() {
+        let servant = unweighted(2);
+        let empty = Maglev::new(&[]);
+        assert!(empty.table.is_empty());
+        assert!(empty.pick(&servant, Some(7), &[true, true]).is_some());
+        let maglev = Maglev::new(&servers(&[None; 2]));
+        assert_eq!(maglev.pick(&servant, Some(7), &[false, false]), None);
+    }
+}
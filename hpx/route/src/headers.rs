@@ -0,0 +1,182 @@
+use hyper::http::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Variables header rule values may refer to as `{name}`, braces around
+/// anything but a name are literal.
+pub const HEADER_VARIABLES: &[&str] =
+    &["client_ip", "servant", "upstream", "trace_id", "request_id"];
+
+/// Changes to the headers of a request or response. Removals are applied
+/// first, then `set`, `add` and `append`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct HeaderRules {
+    /// headers replacing any of the same name
+    #[serde(rename = "set", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub set: BTreeMap<String, String>,
+    /// headers only added when missing
+    #[serde(rename = "add", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub add: BTreeMap<String, String>,
+    /// values added next to the existing ones
+    #[serde(rename = "append", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub append: BTreeMap<String, String>,
+    #[serde(rename = "remove", default, skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+}
+
+impl HeaderRules {
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+            && self.add.is_empty()
+            && self.append.is_empty()
+            && self.remove.is_empty()
+    }
+
+    /// Apply the rules to `headers`, `var` resolves the variables used in
+    /// values, unresolved ones are left empty.
+    pub fn apply(&self, headers: &mut HeaderMap, var: &dyn Fn(&str) -> Option<String>) {
+        self.remove.iter().for_each(|name| {
+            headers.remove(name.as_str());
+        });
+        for (name, value) in &self.set {
+            if let Some((name, value)) = header(name, value, var) {
+                headers.insert(name, value);
+            }
+        }
+        for (name, value) in &self.add {
+            if headers.contains_key(name.as_str()) {
+                continue;
+            }
+            if let Some((name, value)) = header(name, value, var) {
+                headers.insert(name, value);
+            }
+        }
+        for (name, value) in &self.append {
+            if let Some((name, value)) = header(name, value, var) {
+                headers.append(name, value);
+            }
+        }
+    }
+}
+
+fn header(
+    name: &str,
+    value: &str,
+    var: &dyn Fn(&str) -> Option<String>,
+) -> Option<(HeaderName, HeaderValue)> {
+    let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+    let value = HeaderValue::from_str(&render(value, var)).ok()?;
+    Some((name, value))
+}
+
+/// `value` with its variables replaced.
+fn render(value: &str, var: &dyn Fn(&str) -> Option<String>) -> String {
+    let mut rendered = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some((start, name)) = next_variable(rest) {
+        rendered.push_str(&rest[..start]);
+        match HEADER_VARIABLES.contains(&name) {
+            true => rendered.push_str(&var(name).unwrap_or_default()),
+            false => rendered.push_str(&rest[start..start + name.len() + 2]),
+        }
+        rest = &rest[start + name.len() + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// The `{name}` placeholders in a header rule value.
+pub(crate) fn variables(value: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = value;
+    while let Some((start, name)) = next_variable(rest) {
+        names.push(name);
+        rest = &rest[start + name.len() + 2..];
+    }
+    names
+}
+
+/// The first `{name}` of `value` and where it starts, `name` being a letter
+/// or `_` followed by letters, digits or `_`.
+fn next_variable(value: &str) -> Option<(usize, &str)> {
+    let mut from = 0;
+    loop {
+        let start = from + value[from..].find('{')?;
+        let name = &value[start + 1..];
+        let len = name
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(name.len());
+        let identifier = name.chars().next().is_some_and(|c| !c.is_ascii_digit());
+        if len > 0 && identifier && name[len..].starts_with('}') {
+            return Some((start, &name[..len]));
+        }
+        from = start + 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn var(name: &str) -> Option<String> {
+        match name {
+            "client_ip" => Some("10.0.0.9".into()),
+            "upstream" => Some("10.0.0.1:80".into()),
+            _ => None,
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn render_replaces_known_variables() {
+        assert_eq!(render("ip={client_ip}", &var), "ip=10.0.0.9");
+        assert_eq!(
+            render("{client_ip}-{upstream}", &var),
+            "10.0.0.9-10.0.0.1:80"
+        );
+        assert_eq!(render("{trace_id}", &var), "");
+        assert_eq!(render("{other}", &var), "{other}");
+    }
+
+    #[test]
+    fn braces_around_anything_but_a_name_are_literal() {
+        assert_eq!(render(r#"{"a": 1}"#, &var), r#"{"a": 1}"#);
+        assert_eq!(render("{1} {} {client_ip", &var), "{1} {} {client_ip");
+        assert_eq!(render("{{client_ip}}", &var), "{10.0.0.9}");
+        assert_eq!(variables(r#"{"ip": "{client_ip}"}"#), vec!["client_ip"]);
+        assert!(variables("{ x }").is_empty());
+    }
+
+    #[test]
+    fn apply_removes_then_sets_adds_and_appends() {
+        let rules: HeaderRules = serde_json::from_value(json!({
+            "remove": ["x-debug", "x-set"],
+            "set": {"x-set": "{client_ip}", "x-replaced": "new"},
+            "add": {"x-added": "1", "x-kept": "new"},
+            "append": {"x-list": "{upstream}"}
+        }))
+        .unwrap();
+        let mut headers = headers(&[
+            ("x-debug", "1"),
+            ("x-replaced", "old"),
+            ("x-kept", "old"),
+            ("x-list", "a"),
+        ]);
+        rules.apply(&mut headers, &var);
+        assert!(!headers.contains_key("x-debug"));
+        assert_eq!(headers["x-set"], "10.0.0.9");
+        assert_eq!(headers["x-replaced"], "new");
+        assert_eq!(headers["x-added"], "1");
+        assert_eq!(headers["x-kept"], "old");
+        let list = headers.get_all("x-list").iter().collect::<Vec<_>>();
+        assert_eq!(list, vec!["a", "10.0.0.1:80"]);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::{ServantState, ServerState};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+
+/// Active health check of a servant's servers.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HealthCheck {
+    #[serde(rename = "path")]
+    pub path: String,
+    /// seconds between two probes of a server
+    #[serde(rename = "interval", default = "default_interval")]
+    pub interval: u64,
+    /// seconds a probe may take before it counts as failed
+    #[serde(rename = "timeout", default = "default_timeout")]
+    pub timeout: u64,
+    #[serde(rename = "healthy_threshold", default = "default_healthy_threshold")]
+    pub healthy_threshold: usize,
+    #[serde(
+        rename = "unhealthy_threshold",
+        default = "default_unhealthy_threshold"
+    )]
+    pub unhealthy_threshold: usize,
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+fn default_timeout() -> u64 {
+    2
+}
+
+fn default_healthy_threshold() -> usize {
+    2
+}
+
+fn default_unhealthy_threshold() -> usize {
+    3
+}
+
+impl ServerState {
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Count a probe result, returns the new health when the server crossed
+    /// one of the thresholds.
+    pub fn record_probe(&self, passed: bool, check: &HealthCheck) -> Option<bool> {
+        let (streak, other) = match passed {
+            true => (&self.passes, &self.fails),
+            false => (&self.fails, &self.passes),
+        };
+        other.store(0, Ordering::Relaxed);
+        let n = streak.fetch_add(1, Ordering::Relaxed) + 1;
+        let threshold = match passed {
+            true => check.healthy_threshold,
+            false => check.unhealthy_threshold,
+        };
+        if n >= threshold && self.healthy.swap(passed, Ordering::Relaxed) != passed {
+            return Some(passed);
+        }
+        None
+    }
+}
+
+impl ServantState {
+    /// Servers the balancer may pick, healthy and not ejected. When every
+    /// server is down traffic is spread over all of them rather than failing
+    /// every request.
+    pub fn available(&self) -> Vec<bool> {
+        let available = self
+            .servers
+            .iter()
+            .map(|s| s.is_healthy() && !s.is_ejected())
+            .collect::<Vec<_>>();
+        if available.iter().any(|up| *up) {
+            return available;
+        }
+        vec![true; available.len()]
+    }
+}
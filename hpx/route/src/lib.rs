@@ -1,47 +1,224 @@
+use hyper::http::Request;
 use radix_trie::Trie;
 use serde::{de, Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
 
 use std::sync::atomic::AtomicUsize;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-pub type RouteMap = HashMap<String, usize>;
+mod balance;
+mod circuit;
+mod hash;
+mod headers;
+mod health;
+mod matcher;
+mod middleware;
+mod mirror;
+mod outlier;
+mod pattern;
+mod retry;
+mod rewrite;
+mod snapshot;
+mod split;
+#[cfg(test)]
+mod testing;
+mod validate;
+mod vhost;
 
-pub type RouteRadixTrie = Trie<String, usize>;
+pub use balance::*;
+pub use circuit::*;
+pub use hash::*;
+pub use headers::*;
+pub use health::*;
+pub use matcher::*;
+pub use middleware::*;
+pub use mirror::*;
+pub use outlier::*;
+pub use pattern::*;
+pub use retry::*;
+pub use rewrite::*;
+pub use snapshot::*;
+pub use split::*;
+pub use validate::*;
+pub use vhost::*;
+
+/// `(specificity, rule)` of the routes sharing a path, most specific first.
+pub type RouteRules = Vec<(usize, usize)>;
+
+pub type RouteMap = HashMap<String, RouteRules>;
+
+pub type RouteRadixTrie = Trie<String, RouteRules>;
 
 pub type EndpointsMap = HashMap<String, RouteEndpoint>;
 
 #[derive(Debug, Default)]
 pub struct Route {
     pub servant: Vec<Servant>,
-    pub rmap: RouteMap,
-    pub rtrie: RouteRadixTrie,
+    /// matchable routes, the virtual hosts index into it
+    pub rules: Vec<RouteRule>,
+    pub vhosts: VirtualHosts,
+    /// the registered endpoints the route was built from
+    pub endpoints: EndpointsMap,
+    /// set when the route is installed, `0` before the first registration
+    pub version: u64,
+}
+
+#[derive(Debug)]
+pub struct RouteRule {
+    /// index of the servant in `Route.servant`
+    pub servant: usize,
+    /// `(servant, weight)` of a split route, indexing into `Route.servant`
+    pub split: Vec<(usize, u32)>,
+    /// index of the servant requests are mirrored to
+    pub mirror: Option<usize>,
+    pub path: RoutePath,
+}
+
+/// A matched route rule and the variables its path captured.
+#[derive(Debug)]
+pub struct RouteMatch<'a> {
+    /// index of the rule in `Route.rules`
+    pub index: usize,
+    pub rule: &'a RouteRule,
+    pub params: PathParams,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Servant {
     pub name: String,
     pub servers: Vec<Server>,
-    #[serde(skip_serializing, skip_deserializing)]
+    #[serde(default)]
+    pub lb_policy: LbPolicy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash_key: Option<HashKey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheck>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outlier_detection: Option<OutlierDetection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub circuit_breaker: Option<CircuitBreaker>,
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub request_headers: HeaderRules,
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub response_headers: HeaderRules,
+    #[serde(skip_deserializing)]
     pub state: Arc<ServantState>,
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Debug)]
 pub struct ServantState {
+    #[serde(skip)]
     pub count: AtomicUsize,
+    /// current weights of the smooth weighted round-robin, one per server
+    #[serde(skip)]
+    pub current_weights: Mutex<Vec<i64>>,
+    /// shared with the next route for the servers it keeps
+    pub servers: Vec<Arc<ServerState>>,
+    /// attempts on the wire to any of the servers
+    pub requests: AtomicUsize,
+    /// requests accepted and waiting for a response
+    pub pending: AtomicUsize,
+    /// retries in flight
+    pub retries: AtomicUsize,
+    #[serde(skip)]
+    pub balancer: Box<dyn LoadBalancer>,
+    #[serde(skip)]
+    pub outlier: Option<OutlierDetection>,
+    #[serde(skip)]
+    pub breaker: Option<CircuitBreaker>,
+}
+
+impl ServantState {
+    pub fn new(servers: &[Server], ep: &RouteEndpoint) -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            current_weights: Mutex::new(vec![0; servers.len()]),
+            servers: servers
+                .iter()
+                .map(|s| Arc::new(ServerState::new(&s.addr)))
+                .collect(),
+            requests: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+            retries: AtomicUsize::new(0),
+            balancer: ep.lb_policy.build(servers),
+            outlier: ep.outlier_detection.clone(),
+            breaker: ep.circuit_breaker.clone(),
+        }
+    }
+
+    /// Take over the state of the servers `prev` had too, matched by address,
+    /// so their health and ejections survive a change of the servant.
+    fn carry_over(mut self, prev: &ServantState) -> Self {
+        let mut kept = HashMap::new();
+        for server in &prev.servers {
+            kept.entry(server.addr.as_str()).or_insert(server);
+        }
+        for server in self.servers.iter_mut() {
+            if let Some(prev) = kept.remove(server.addr.as_str()) {
+                *server = prev.clone();
+            }
+        }
+        self
+    }
+}
+
+impl Default for ServantState {
+    fn default() -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            current_weights: Mutex::default(),
+            servers: vec![],
+            requests: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+            retries: AtomicUsize::new(0),
+            balancer: LbPolicy::default().build(&[]),
+            outlier: None,
+            breaker: None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Server {
     pub addr: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    weight: Option<i8>,
+    pub weight: Option<i8>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// An endpoint in the register payload, either `"host:port"` or
+/// `{"addr": "host:port", "weight": 5}`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Endpoint {
+    Addr(String),
+    Weighted { addr: String, weight: i8 },
+}
+
+impl Endpoint {
+    pub fn addr(&self) -> &str {
+        match self {
+            Endpoint::Addr(addr) => addr,
+            Endpoint::Weighted { addr, .. } => addr,
+        }
+    }
+
+    pub fn weight(&self) -> Option<i8> {
+        match self {
+            Endpoint::Addr(_) => None,
+            Endpoint::Weighted { weight, .. } => Some(*weight),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum RouteKind {
     Precise,
     Fuzzy,
+    /// a regex matching the whole path
+    Regex,
+    /// a path with `{name}` variables, each matching one segment
+    Template,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -49,15 +226,170 @@ pub struct RouteEndpoint {
     #[serde(rename = "routes")]
     pub routes: Vec<RoutePath>,
     #[serde(rename = "endpoints")]
-    pub endpoints: Vec<String>,
+    pub endpoints: Vec<Endpoint>,
+    #[serde(rename = "lb_policy", default)]
+    pub lb_policy: LbPolicy,
+    #[serde(rename = "hash_key", default, skip_serializing_if = "Option::is_none")]
+    pub hash_key: Option<HashKey>,
+    #[serde(
+        rename = "health_check",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub health_check: Option<HealthCheck>,
+    #[serde(
+        rename = "outlier_detection",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub outlier_detection: Option<OutlierDetection>,
+    #[serde(
+        rename = "circuit_breaker",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// header rules of all the servant's routes, applied before a route's own
+    #[serde(
+        rename = "request_headers",
+        default,
+        skip_serializing_if = "HeaderRules::is_empty"
+    )]
+    pub request_headers: HeaderRules,
+    #[serde(
+        rename = "response_headers",
+        default,
+        skip_serializing_if = "HeaderRules::is_empty"
+    )]
+    pub response_headers: HeaderRules,
+}
+
+/// A servant as registered, the payload of `/route/register` is a list of them.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServiceRoute {
+    #[serde(rename = "servant")]
+    pub servant: String,
+    #[serde(flatten)]
+    pub endpoint: RouteEndpoint,
+}
+
+impl ServiceRoute {
+    /// The registration payload of `ep`, ordered by servant.
+    pub fn from_endpoints(ep: &EndpointsMap) -> Vec<ServiceRoute> {
+        let mut routes = ep
+            .iter()
+            .map(|(k, v)| ServiceRoute {
+                servant: k.clone(),
+                endpoint: v.clone(),
+            })
+            .collect::<Vec<_>>();
+        routes.sort_by(|a, b| a.servant.cmp(&b.servant));
+        routes
+    }
+}
+
+impl RouteEndpoint {
+    /// Whether both sides balance over the same servers the same way, routes
+    /// aside, so a rebuilt servant may keep its state.
+    pub fn same_servers(&self, other: &RouteEndpoint) -> bool {
+        self.endpoints == other.endpoints
+            && self.lb_policy == other.lb_policy
+            && self.hash_key == other.hash_key
+            && self.health_check == other.health_check
+            && self.outlier_detection == other.outlier_detection
+            && self.circuit_breaker == other.circuit_breaker
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RoutePath {
     #[serde(rename = "path")]
-    path: String,
+    pub path: String,
     #[serde(rename = "kind", deserialize_with = "de_route_kind")]
-    kind: RouteKind,
+    pub kind: RouteKind,
+    /// hosts the route is served on, `*.example.internal` for any subdomain,
+    /// routes without hosts are served on the default virtual host
+    #[serde(rename = "hosts", default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+    /// methods the route is limited to, any when empty
+    #[serde(rename = "methods", default, skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<String>,
+    /// headers the request must carry
+    #[serde(rename = "headers", default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<HeaderMatch>,
+    /// query parameters the request must carry with the given values
+    #[serde(rename = "query", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub query: BTreeMap<String, String>,
+    /// servants sharing the route's traffic by weight, instead of the
+    /// servant registering it
+    #[serde(rename = "split", default, skip_serializing_if = "Vec::is_empty")]
+    pub split: Vec<SplitTarget>,
+    /// keeps requests with the same key on the same split target
+    #[serde(rename = "sticky", default, skip_serializing_if = "Option::is_none")]
+    pub sticky: Option<HashKey>,
+    /// servant a share of the requests is copied to
+    #[serde(rename = "mirror", default, skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<Mirror>,
+    /// replaces the matched path prefix, the whole path of other route kinds
+    #[serde(
+        rename = "prefix_rewrite",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub prefix_rewrite: Option<String>,
+    #[serde(
+        rename = "regex_rewrite",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub regex_rewrite: Option<RegexRewrite>,
+    /// `Host` sent to the upstream
+    #[serde(
+        rename = "host_rewrite",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub host_rewrite: Option<String>,
+    /// changes to the headers of the request sent upstream
+    #[serde(
+        rename = "request_headers",
+        default,
+        skip_serializing_if = "HeaderRules::is_empty"
+    )]
+    pub request_headers: HeaderRules,
+    /// changes to the headers of the response sent back
+    #[serde(
+        rename = "response_headers",
+        default,
+        skip_serializing_if = "HeaderRules::is_empty"
+    )]
+    pub response_headers: HeaderRules,
+    /// middleware chain of the route, replacing the default one
+    #[serde(
+        rename = "middlewares",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub middlewares: Option<Vec<MiddlewareSpec>>,
+    #[serde(rename = "retry", default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+    /// seconds to wait for the upstream response, overrides `REQUEST_TIMEOUT`
+    #[serde(rename = "timeout", default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// seconds the upstream response body may stall, overrides `IDLE_TIMEOUT`
+    #[serde(
+        rename = "idle_timeout",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub idle_timeout: Option<u64>,
+    /// bytes the request body may carry, overrides `MAX_BODY_SIZE`
+    #[serde(
+        rename = "max_body_size",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_body_size: Option<usize>,
 }
 
 fn de_route_kind<'de, D>(deserializer: D) -> Result<RouteKind, D::Error>
@@ -68,6 +400,8 @@ where
     let op = match s.as_str() {
         "precise" => RouteKind::Precise,
         "fuzzy" => RouteKind::Fuzzy,
+        "regex" => RouteKind::Regex,
+        "template" => RouteKind::Template,
         other => {
             return Err(de::Error::custom(format!(
                 "Invalid query param kind '{}'",
@@ -80,38 +414,226 @@ where
 
 impl Route {
     pub fn from_endpoints(ep: &EndpointsMap) -> Result<Self, std::io::Error> {
-        let mut rmap = RouteMap::new();
-        let mut rtrie = RouteRadixTrie::new();
+        Self::build(ep, None)
+    }
+
+    /// Build the route for `ep` on top of this one, servants whose servers are
+    /// unchanged keep their `ServantState`, the others keep the state of the
+    /// servers they still have.
+    pub fn update(&self, ep: &EndpointsMap) -> Result<Self, std::io::Error> {
+        Self::build(ep, Some(self))
+    }
+
+    fn build(ep: &EndpointsMap, prev: Option<&Route>) -> Result<Self, std::io::Error> {
+        validate(ep).map_err(std::io::Error::other)?;
+        let mut vhosts = VirtualHosts::default();
         let mut servants = Vec::new();
+        let mut rules = Vec::new();
         let mut cursor = 0;
-        ep.iter().for_each(|(k, v)| {
+        // servants are walked by name so rules of equal specificity keep the
+        // same order, whatever the order of the map
+        let mut sorted = ep.iter().collect::<Vec<_>>();
+        sorted.sort_by(|a, b| a.0.cmp(b.0));
+        let indexes = sorted
+            .iter()
+            .enumerate()
+            .map(|(i, (k, _))| (k.as_str(), i))
+            .collect::<HashMap<_, _>>();
+        sorted.into_iter().for_each(|(k, v)| {
             let servers = v
                 .endpoints
                 .iter()
                 .map(|server| Server {
-                    addr: server.into(),
-                    weight: None,
+                    addr: server.addr().into(),
+                    weight: server.weight(),
                 })
                 .collect::<Vec<Server>>();
             let servant = Servant {
                 name: k.clone(),
-                state: Arc::new(ServantState::default()),
+                lb_policy: v.lb_policy,
+                hash_key: v.hash_key.clone(),
+                health_check: v.health_check.clone(),
+                outlier_detection: v.outlier_detection.clone(),
+                circuit_breaker: v.circuit_breaker.clone(),
+                request_headers: v.request_headers.clone(),
+                response_headers: v.response_headers.clone(),
+                state: match prev.and_then(|p| p.servant_state(k)) {
+                    Some((ep, state)) if ep.same_servers(v) => state.clone(),
+                    Some((_, state)) => Arc::new(ServantState::new(&servers, v).carry_over(state)),
+                    None => Arc::new(ServantState::new(&servers, v)),
+                },
                 servers,
             };
             let index = cursor;
             servants.push(servant);
             cursor += 1;
             v.routes.iter().for_each(|r| {
-                match r.kind {
-                    RouteKind::Precise => rmap.insert(r.path.clone(), index),
-                    RouteKind::Fuzzy => rtrie.insert(r.path.clone(), index),
-                };
+                let rule = (r.specificity(), rules.len());
+                if r.hosts.is_empty() {
+                    vhosts.entry(None).insert(r.kind, &r.path, rule);
+                }
+                r.hosts.iter().for_each(|host| {
+                    vhosts.entry(Some(host)).insert(r.kind, &r.path, rule);
+                });
+                let split = r
+                    .split
+                    .iter()
+                    .filter_map(|t| Some((*indexes.get(t.servant.as_str())?, t.weight)))
+                    .collect();
+                let mirror = r
+                    .mirror
+                    .as_ref()
+                    .and_then(|m| indexes.get(m.servant.as_str()).copied());
+                rules.push(RouteRule {
+                    servant: index,
+                    split,
+                    mirror,
+                    path: r.clone(),
+                });
             });
         });
+        vhosts.compile().map_err(std::io::Error::other)?;
         Ok(Self {
             servant: servants,
-            rmap,
-            rtrie,
+            rules,
+            vhosts,
+            endpoints: ep.clone(),
+            version: 0,
         })
     }
+
+    fn servant_state(&self, name: &str) -> Option<(&RouteEndpoint, &Arc<ServantState>)> {
+        let servant = self.servant.iter().find(|s| s.name == name)?;
+        Some((self.endpoints.get(name)?, &servant.state))
+    }
+
+    /// The route rule matching a request, looked up by path in the virtual
+    /// host of the request's host.
+    pub fn find<B>(&self, req: &Request<B>) -> Option<RouteMatch<'_>> {
+        let vhost = self.vhosts.select(request_host(req));
+        let (index, params) = vhost.find(req.uri().path(), |i| self.rules[i].path.matches(req))?;
+        Some(RouteMatch {
+            index,
+            rule: self.rules.get(index)?,
+            params,
+        })
+    }
+}
+
+impl Servant {
+    /// Pick a server with the servant's balancer and count the request as
+    /// outstanding on it until the returned guard is dropped, the guard also
+    /// holds the attempt reserved by `slot`. Servers listed in `tried` are
+    /// only picked again when no other server is available.
+    pub fn select<B>(
+        &self,
+        req: &Request<B>,
+        peer: SocketAddr,
+        tried: &[usize],
+        slot: RequestSlot,
+    ) -> Option<(&Server, InFlight)> {
+        let hash = self.hash_key.as_ref().and_then(|k| k.hash(req, peer));
+        let mut available = self.state.available();
+        if available
+            .iter()
+            .enumerate()
+            .any(|(i, up)| *up && !tried.contains(&i))
+        {
+            tried.iter().for_each(|i| available[*i] = false);
+        }
+        let index = self.state.balancer.pick(self, hash, &available)?;
+        Some((
+            &self.servers[index],
+            InFlight::new(self.state.clone(), index, slot),
+        ))
+    }
+}
+
+impl Server {
+    /// Weight used by the balancer, unweighted servers count as `1` and
+    /// negative weights are treated as drained.
+    pub fn effective_weight(&self) -> i64 {
+        self.weight.map_or(1, |w| w.max(0) as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::Ordering;
+
+    fn endpoints(routes: serde_json::Value) -> EndpointsMap {
+        let routes: Vec<ServiceRoute> = serde_json::from_value(routes).unwrap();
+        ServiceRoute::validate(&routes).unwrap()
+    }
+
+    #[test]
+    fn updates_keep_the_state_of_remaining_servers() {
+        let route = Route::from_endpoints(&endpoints(json!([{"servant": "a", "routes": [],
+            "endpoints": ["10.0.0.1:80", "10.0.0.2:80"]}])))
+        .unwrap();
+        route.servant[0].state.servers[1]
+            .healthy
+            .store(false, Ordering::Relaxed);
+
+        let same = route
+            .update(&endpoints(
+                json!([{"servant": "a", "routes": [{"path": "/a", "kind": "precise"}],
+                "endpoints": ["10.0.0.1:80", "10.0.0.2:80"]}]),
+            ))
+            .unwrap();
+        assert!(Arc::ptr_eq(&same.servant[0].state, &route.servant[0].state));
+
+        let changed = same
+            .update(&endpoints(json!([{"servant": "a", "routes": [],
+                "endpoints": ["10.0.0.2:80", "10.0.0.3:80"]}])))
+            .unwrap();
+        let servers = &changed.servant[0].state.servers;
+        assert!(Arc::ptr_eq(&servers[0], &route.servant[0].state.servers[1]));
+        assert!(!servers[0].is_healthy());
+        assert!(servers[1].is_healthy());
+    }
+
+    /// The servant of the rule `uri` matched, over routes built from fresh
+    /// maps, each walked in its own order.
+    fn matched_servants(routes: serde_json::Value, uri: &str) -> Vec<String> {
+        (0..16)
+            .map(|_| {
+                let route = Route::from_endpoints(&endpoints(routes.clone())).unwrap();
+                let req = Request::get(uri).body(()).unwrap();
+                let rule = route.find(&req).unwrap().rule;
+                route.servant[rule.servant].name.clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn equally_specific_rules_go_by_servant_name() {
+        // each servant asks for its own query parameter, a request carrying
+        // all of them matches every rule
+        let servants = ["d", "b", "a", "c"]
+            .iter()
+            .map(|name| {
+                json!({"servant": name, "endpoints": ["10.0.0.1:80"],
+                "routes": [{"path": "/same", "kind": "precise", "query": {*name: "1"}}]})
+            })
+            .collect::<Vec<_>>();
+        let matched = matched_servants(json!(servants), "/same?a=1&b=1&c=1&d=1");
+        assert!(matched.iter().all(|name| name == "a"), "{:?}", matched);
+    }
+
+    #[test]
+    fn overlapping_patterns_go_by_servant_name() {
+        let servants = json!([
+            {"servant": "z", "endpoints": ["10.0.0.1:80"],
+                "routes": [{"path": "/users/{id}", "kind": "template"}]},
+            {"servant": "y", "endpoints": ["10.0.0.1:80"],
+                "routes": [{"path": "^/users/[0-9]+$", "kind": "regex"}]},
+            {"servant": "x", "endpoints": ["10.0.0.1:80"],
+                "routes": [{"path": "^/users/.*$", "kind": "regex"}]},
+        ]);
+        let matched = matched_servants(servants, "/users/42");
+        assert!(matched.iter().all(|name| name == "x"), "{:?}", matched);
+    }
 }
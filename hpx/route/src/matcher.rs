@@ -0,0 +1,158 @@
+use crate::RoutePath;
+use hyper::http::Request;
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+/// A header a route requires, with any value unless `exact` or `regex` is
+/// given.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HeaderMatch {
+    #[serde(rename = "name")]
+    pub name: String,
+    #[serde(rename = "exact", default, skip_serializing_if = "Option::is_none")]
+    pub exact: Option<String>,
+    #[serde(rename = "regex", default, skip_serializing_if = "Option::is_none")]
+    pub regex: Option<MatchRegex>,
+}
+
+/// A regex matched against a whole value, compiled when the route is parsed.
+#[derive(Clone)]
+pub struct MatchRegex(Regex);
+
+impl MatchRegex {
+    pub fn is_match(&self, value: &str) -> bool {
+        self.0.is_match(value)
+    }
+}
+
+impl fmt::Debug for MatchRegex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0.as_str())
+    }
+}
+
+impl Serialize for MatchRegex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // strip the anchors added on parse
+        let pattern = self.0.as_str();
+        serializer.serialize_str(&pattern[4..pattern.len() - 2])
+    }
+}
+
+impl<'de> Deserialize<'de> for MatchRegex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern)
+            .and_then(|_| Regex::new(&format!("^(?:{})$", pattern)))
+            .map(MatchRegex)
+            .map_err(|e| de::Error::custom(format!("Invalid regex '{}': {}", pattern, e)))
+    }
+}
+
+impl HeaderMatch {
+    fn matches<B>(&self, req: &Request<B>) -> bool {
+        if self.exact.is_none() && self.regex.is_none() {
+            return req.headers().contains_key(self.name.as_str());
+        }
+        req.headers()
+            .get_all(self.name.as_str())
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|value| {
+                self.exact.as_ref().is_none_or(|exact| exact == value)
+                    && self
+                        .regex
+                        .as_ref()
+                        .is_none_or(|regex| regex.is_match(value))
+            })
+    }
+}
+
+impl RoutePath {
+    /// Whether the request meets the route's method, header and query
+    /// conditions, the path is matched by the virtual host. Query parameters
+    /// are compared decoded.
+    pub fn matches<B>(&self, req: &Request<B>) -> bool {
+        let method = req.method().as_str();
+        if !self.methods.is_empty() && !self.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+        {
+            return false;
+        }
+        if !self.headers.iter().all(|h| h.matches(req)) {
+            return false;
+        }
+        if self.query.is_empty() {
+            return true;
+        }
+        let query = req.uri().query().unwrap_or_default().as_bytes();
+        self.query.iter().all(|(name, value)| {
+            form_urlencoded::parse(query).any(|(k, v)| k == name.as_str() && v == value.as_str())
+        })
+    }
+
+    /// Number of conditions on the route, of the routes sharing a path the
+    /// most specific matching one wins.
+    pub fn specificity(&self) -> usize {
+        (!self.methods.is_empty()) as usize + self.headers.len() + self.query.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::RoutePath;
+    use hyper::http::Request;
+    use serde_json::json;
+
+    fn route(conditions: serde_json::Value) -> RoutePath {
+        let mut route = json!({"path": "/", "kind": "precise"});
+        route
+            .as_object_mut()
+            .unwrap()
+            .extend(conditions.as_object().unwrap().clone());
+        serde_json::from_value(route).unwrap()
+    }
+
+    fn request(uri: &str, headers: &[(&str, &[u8])]) -> Request<()> {
+        let mut req = Request::post(uri);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(()).unwrap()
+    }
+
+    #[test]
+    fn header_present_with_any_value() {
+        let route = route(json!({"headers": [{"name": "x-canary"}]}));
+        assert!(route.matches(&request("/", &[("x-canary", b"")])));
+        assert!(route.matches(&request("/", &[("x-canary", b"\xff")])));
+        assert!(!route.matches(&request("/", &[])));
+    }
+
+    #[test]
+    fn header_exact_or_regex() {
+        let exact = route(json!({"headers": [{"name": "x-env", "exact": "beta"}]}));
+        assert!(exact.matches(&request("/", &[("x-env", b"alpha"), ("x-env", b"beta")])));
+        assert!(!exact.matches(&request("/", &[("x-env", b"beta2")])));
+        let regex = route(json!({"headers": [{"name": "x-env", "regex": "b[a-z]+"}]}));
+        assert!(regex.matches(&request("/", &[("x-env", b"beta")])));
+        // the regex has to match the whole value
+        assert!(!regex.matches(&request("/", &[("x-env", b"beta2")])));
+    }
+
+    #[test]
+    fn query_values_are_decoded() {
+        let route = route(json!({"query": {"q": "a b/c", "v": "1"}}));
+        assert!(route.matches(&request("/?v=1&q=a%20b%2Fc", &[])));
+        assert!(route.matches(&request("/?q=a+b/c&v=1", &[])));
+        assert!(!route.matches(&request("/?q=a%20b%2Fc", &[])));
+        assert!(!route.matches(&request("/?q=a%20b%2Fcd&v=1", &[])));
+    }
+
+    #[test]
+    fn methods_ignore_case() {
+        let route = route(json!({"methods": ["post"]}));
+        assert!(route.matches(&request("/", &[])));
+        assert!(!route.matches(&Request::get("/").body(()).unwrap()));
+    }
+}
@@ -0,0 +1,12 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// A middleware of a route's chain, by the name it's registered under, with
+/// the options it's built from.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MiddlewareSpec {
+    #[serde(rename = "name")]
+    pub name: String,
+    #[serde(flatten)]
+    pub options: Map<String, Value>,
+}
@@ -0,0 +1,23 @@
+use serde::{Deserialize, Serialize};
+
+/// Copies of a route's requests sent to another servant, their responses are
+/// dropped.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Mirror {
+    #[serde(rename = "servant")]
+    pub servant: String,
+    /// percentage of the requests mirrored
+    #[serde(rename = "percent", default = "default_percent")]
+    pub percent: u8,
+    /// seconds a copy may take, its response body included
+    #[serde(rename = "timeout", default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_percent() -> u8 {
+    100
+}
+
+fn default_timeout() -> u64 {
+    10
+}
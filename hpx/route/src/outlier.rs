@@ -0,0 +1,172 @@
+use crate::{ServantState, ServerState};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Passive outlier detection, ejects servers that keep failing real traffic.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OutlierDetection {
+    /// connect errors or 5xx responses in a row before a server is ejected
+    #[serde(rename = "consecutive_errors", default = "default_consecutive_errors")]
+    pub consecutive_errors: usize,
+    /// seconds of the first ejection, doubled on every ejection that follows
+    #[serde(rename = "base_ejection_time", default = "default_base_ejection_time")]
+    pub base_ejection_time: u64,
+    /// seconds an ejection is capped at, a server that stayed in this long
+    /// starts over from `base_ejection_time`
+    #[serde(rename = "max_ejection_time", default = "default_max_ejection_time")]
+    pub max_ejection_time: u64,
+    #[serde(
+        rename = "max_ejection_percent",
+        default = "default_max_ejection_percent"
+    )]
+    pub max_ejection_percent: usize,
+}
+
+fn default_consecutive_errors() -> usize {
+    5
+}
+
+fn default_base_ejection_time() -> u64 {
+    30
+}
+
+fn default_max_ejection_time() -> u64 {
+    300
+}
+
+fn default_max_ejection_percent() -> usize {
+    10
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+impl ServerState {
+    pub fn is_ejected(&self) -> bool {
+        self.ejected_until.load(Ordering::Relaxed) > now_millis()
+    }
+}
+
+impl ServantState {
+    /// Count the outcome of a request to server `index`, returns the ejection
+    /// time when the failure got the server ejected.
+    pub fn record_outcome(&self, index: usize, failed: bool) -> Option<Duration> {
+        let detection = self.outlier.as_ref()?;
+        let server = &self.servers[index];
+        if !failed {
+            server.errors.store(0, Ordering::Relaxed);
+            return None;
+        }
+        let errors = server.errors.fetch_add(1, Ordering::Relaxed) + 1;
+        if errors < detection.consecutive_errors || server.is_ejected() {
+            return None;
+        }
+        let ejected = self.servers.iter().filter(|s| s.is_ejected()).count();
+        let len = self.servers.len();
+        // stay within `max_ejection_percent`, though one server may always be
+        // ejected so small servants are covered, and never eject the last
+        // server still taking traffic
+        let within = ejected == 0 || (ejected + 1) * 100 <= detection.max_ejection_percent * len;
+        let others_up = self
+            .servers
+            .iter()
+            .enumerate()
+            .any(|(i, s)| i != index && s.is_healthy() && !s.is_ejected());
+        if !within || !others_up {
+            return None;
+        }
+        let now = now_millis();
+        let max = detection.max_ejection_time * 1000;
+        if now.saturating_sub(server.ejected_until.load(Ordering::Relaxed)) > max {
+            server.ejections.store(0, Ordering::Relaxed);
+        }
+        let ejections = server.ejections.fetch_add(1, Ordering::Relaxed) as u32;
+        let millis = (detection.base_ejection_time * 1000)
+            .saturating_mul(2u64.saturating_pow(ejections))
+            .min(max);
+        server.ejected_until.store(now + millis, Ordering::Relaxed);
+        server.errors.store(0, Ordering::Relaxed);
+        Some(Duration::from_millis(millis))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::now_millis;
+    use crate::testing::servant;
+    use crate::{Servant, ServantState};
+    use serde_json::json;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    fn detecting(n: usize, max_ejection_percent: usize) -> Servant {
+        let endpoints = (0..n)
+            .map(|i| format!("10.0.0.{}:80", i))
+            .collect::<Vec<_>>();
+        servant(
+            json!({"routes": [], "endpoints": endpoints, "outlier_detection": {
+                "consecutive_errors": 2, "base_ejection_time": 10, "max_ejection_time": 30,
+                "max_ejection_percent": max_ejection_percent
+            }}),
+        )
+    }
+
+    #[test]
+    fn ejects_after_consecutive_errors() {
+        let state = detecting(4, 50).state;
+        assert_eq!(state.record_outcome(0, true), None);
+        assert_eq!(state.record_outcome(0, false), None);
+        assert_eq!(state.record_outcome(0, true), None);
+        assert_eq!(state.record_outcome(0, true), Some(Duration::from_secs(10)));
+        assert!(state.servers[0].is_ejected());
+    }
+
+    #[test]
+    fn ejection_time_doubles_up_to_the_max() {
+        let state = detecting(4, 50).state;
+        let mut times = vec![];
+        for _ in 0..3 {
+            state.record_outcome(0, true);
+            times.push(state.record_outcome(0, true).unwrap().as_secs());
+            // the ejection ended just now
+            let ended = now_millis() - 1;
+            state.servers[0]
+                .ejected_until
+                .store(ended, Ordering::Relaxed);
+        }
+        assert_eq!(times, vec![10, 20, 30]);
+    }
+
+    #[test]
+    fn max_ejection_percent_caps_every_ejection() {
+        let eject = |state: &ServantState, i| {
+            state.record_outcome(i, true);
+            state.record_outcome(i, true).is_some()
+        };
+        let state = detecting(4, 50).state;
+        let ejected = (eject(&state, 0), eject(&state, 1), eject(&state, 2));
+        assert_eq!(ejected, (true, true, false));
+
+        // below one server the cap still lets a single ejection through
+        let state = detecting(4, 10).state;
+        assert_eq!((eject(&state, 0), eject(&state, 1)), (true, false));
+    }
+
+    #[test]
+    fn never_ejects_the_last_server_taking_traffic() {
+        let state = detecting(2, 100).state;
+        state.servers[1].healthy.store(false, Ordering::Relaxed);
+        state.record_outcome(0, true);
+        assert_eq!(state.record_outcome(0, true), None);
+
+        // the errors kept counting while the server could not be ejected
+        state.servers[1].healthy.store(true, Ordering::Relaxed);
+        assert!(state.record_outcome(0, true).is_some());
+        state.record_outcome(1, true);
+        assert_eq!(state.record_outcome(1, true), None);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::RouteKind;
+use regex::Regex;
+use std::fmt;
+
+/// Variables captured from the path by a regex or template route, by name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PathParams(pub Vec<(String, String)>);
+
+impl PathParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn capture(regex: &Regex, path: &str) -> Self {
+        let captures = match regex.captures(path) {
+            Some(captures) => captures,
+            None => return Self::default(),
+        };
+        let params = regex
+            .capture_names()
+            .flatten()
+            .filter_map(|name| Some((name.to_owned(), captures.name(name)?.as_str().to_owned())))
+            .collect();
+        PathParams(params)
+    }
+}
+
+impl fmt::Display for PathParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let params = self
+            .0
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>();
+        write!(f, "{}", params.join(","))
+    }
+}
+
+/// The whole-path regex of a regex or template route, `None` for the others.
+pub fn path_regex(kind: RouteKind, path: &str) -> Option<Result<String, String>> {
+    match kind {
+        RouteKind::Regex => Some(
+            Regex::new(path)
+                .map(|_| format!("^(?:{})$", path))
+                .map_err(|e| e.to_string()),
+        ),
+        RouteKind::Template => Some(template_regex(path)),
+        RouteKind::Precise | RouteKind::Fuzzy => None,
+    }
+}
+
+/// Turn `/users/{id}/orders/{order_id}` into a regex capturing each variable
+/// from one path segment.
+fn template_regex(template: &str) -> Result<String, String> {
+    let mut regex = String::from("^");
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| format!("unclosed '{{' in {}", template))?;
+        let name = &rest[start + 1..end];
+        let valid = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(format!("invalid variable '{}' in {}", name, template));
+        }
+        regex.push_str(&regex::escape(&rest[..start]));
+        regex.push_str(&format!("(?P<{}>[^/]+)", name));
+        rest = &rest[end + 1..];
+    }
+    if rest.contains('}') {
+        return Err(format!("unopened '}}' in {}", template));
+    }
+    regex.push_str(&regex::escape(rest));
+    regex.push('$');
+    Regex::new(&regex).map(|_| regex).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regex(kind: RouteKind, path: &str) -> Regex {
+        Regex::new(&path_regex(kind, path).unwrap().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn templates_capture_one_segment_per_variable() {
+        let regex = regex(RouteKind::Template, "/users/{id}/orders/{order_id}");
+        let params = PathParams::capture(&regex, "/users/7/orders/a-1");
+        assert_eq!(params.get("id"), Some("7"));
+        assert_eq!(params.get("order_id"), Some("a-1"));
+        assert_eq!(params.to_string(), "id=7,order_id=a-1");
+        assert!(!regex.is_match("/users/7/8/orders/a-1"));
+        assert!(!regex.is_match("/users/7/orders/a-1/x"));
+    }
+
+    #[test]
+    fn templates_match_their_literal_parts_only() {
+        let regex = regex(RouteKind::Template, "/v1.0/{id}");
+        assert!(regex.is_match("/v1.0/3"));
+        assert!(!regex.is_match("/v1x0/3"));
+    }
+
+    #[test]
+    fn invalid_templates() {
+        for template in ["/a/{id", "/a/{1d}", "/a/{}", "/a/id}"] {
+            assert!(
+                path_regex(RouteKind::Template, template).unwrap().is_err(),
+                "{}",
+                template
+            );
+        }
+    }
+
+    #[test]
+    fn regexes_match_the_whole_path() {
+        let regex = regex(RouteKind::Regex, "/users/(?P<id>[0-9]+)");
+        assert_eq!(
+            PathParams::capture(&regex, "/users/42").get("id"),
+            Some("42")
+        );
+        assert!(!regex.is_match("/users/42/x"));
+        assert!(!regex.is_match("/api/users/42"));
+        assert!(path_regex(RouteKind::Fuzzy, "/users/").is_none());
+    }
+}
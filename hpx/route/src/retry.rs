@@ -0,0 +1,186 @@
+use crate::ServantState;
+use hyper::http::Method;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Conditions a request is retried on.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum RetryOn {
+    #[serde(rename = "connect-failure")]
+    ConnectFailure,
+    #[serde(rename = "reset")]
+    Reset,
+    #[serde(rename = "502")]
+    BadGateway,
+    #[serde(rename = "503")]
+    ServiceUnavailable,
+    #[serde(rename = "504")]
+    GatewayTimeout,
+    /// gRPC status `unavailable` (14)
+    #[serde(rename = "unavailable")]
+    GrpcUnavailable,
+}
+
+/// Per route retry policy, retries go to another server of the same servant.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    #[serde(rename = "retry_on", default = "default_retry_on")]
+    pub retry_on: Vec<RetryOn>,
+    /// attempts including the first one
+    #[serde(rename = "max_attempts", default = "default_max_attempts")]
+    pub max_attempts: usize,
+    /// milliseconds of the first backoff, doubled on every retry and jittered
+    #[serde(rename = "base_interval", default = "default_base_interval")]
+    pub base_interval: u64,
+    #[serde(rename = "max_interval", default = "default_max_interval")]
+    pub max_interval: u64,
+    /// retries in flight may not exceed this percentage of the servant's
+    /// active requests
+    #[serde(rename = "budget_percent", default = "default_budget_percent")]
+    pub budget_percent: usize,
+    /// retries always allowed in flight, however few requests are active
+    #[serde(rename = "min_retries", default = "default_min_retries")]
+    pub min_retries: usize,
+    /// bytes of request body buffered for replay, larger bodies aren't retried
+    #[serde(rename = "buffer_limit", default = "default_buffer_limit")]
+    pub buffer_limit: usize,
+}
+
+fn default_retry_on() -> Vec<RetryOn> {
+    vec![RetryOn::ConnectFailure]
+}
+
+fn default_max_attempts() -> usize {
+    2
+}
+
+fn default_base_interval() -> u64 {
+    25
+}
+
+fn default_max_interval() -> u64 {
+    250
+}
+
+fn default_budget_percent() -> usize {
+    20
+}
+
+fn default_min_retries() -> usize {
+    3
+}
+
+fn default_buffer_limit() -> usize {
+    64 * 1024
+}
+
+impl RetryPolicy {
+    /// Whether a failed attempt with `reason` may be retried for `method`,
+    /// non-idempotent requests are only retried when they never left hpx.
+    pub fn should_retry(&self, reason: RetryOn, method: &Method) -> bool {
+        self.retry_on.contains(&reason)
+            && (reason == RetryOn::ConnectFailure || is_idempotent(method))
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+impl ServantState {
+    /// Take a slot of the retry budget, `None` when the retries in flight
+    /// already use it up or hit the circuit breaker's `max_retries`.
+    pub fn begin_retry(self: &Arc<Self>, policy: &RetryPolicy) -> Option<RetryGuard> {
+        let active = self.pending.load(Ordering::SeqCst);
+        let budget = (active * policy.budget_percent / 100).max(policy.min_retries);
+        let budget = match &self.breaker {
+            Some(breaker) => budget.min(breaker.max_retries),
+            None => budget,
+        };
+        self.retries
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |retries| {
+                if retries < budget {
+                    Some(retries + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()?;
+        Some(RetryGuard {
+            state: self.clone(),
+        })
+    }
+}
+
+/// A retry in flight, counted against the retry budget until dropped.
+#[derive(Debug)]
+pub struct RetryGuard {
+    state: Arc<ServantState>,
+}
+
+impl Drop for RetryGuard {
+    fn drop(&mut self) {
+        self.state.retries.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetryOn, RetryPolicy};
+    use crate::testing::servant;
+    use hyper::http::Method;
+    use serde_json::json;
+
+    fn policy(policy: serde_json::Value) -> RetryPolicy {
+        serde_json::from_value(policy).unwrap()
+    }
+
+    #[test]
+    fn only_listed_reasons_are_retried() {
+        let policy = policy(json!({"retry_on": ["connect-failure", "503"]}));
+        assert!(policy.should_retry(RetryOn::ConnectFailure, &Method::GET));
+        assert!(policy.should_retry(RetryOn::ServiceUnavailable, &Method::GET));
+        assert!(!policy.should_retry(RetryOn::BadGateway, &Method::GET));
+        assert!(!policy.should_retry(RetryOn::Reset, &Method::GET));
+    }
+
+    #[test]
+    fn non_idempotent_requests_retry_only_before_leaving() {
+        let policy = policy(json!({"retry_on": ["connect-failure", "reset", "503"]}));
+        assert!(policy.should_retry(RetryOn::ConnectFailure, &Method::POST));
+        assert!(!policy.should_retry(RetryOn::Reset, &Method::POST));
+        assert!(!policy.should_retry(RetryOn::ServiceUnavailable, &Method::PATCH));
+        assert!(policy.should_retry(RetryOn::Reset, &Method::PUT));
+    }
+
+    #[test]
+    fn retries_stay_within_the_budget() {
+        let policy = policy(json!({"budget_percent": 50, "min_retries": 1}));
+        let state = servant(json!({"routes": [], "endpoints": ["10.0.0.1:80"]})).state;
+        // no active request, only `min_retries` is allowed
+        let first = state.begin_retry(&policy).unwrap();
+        assert!(state.begin_retry(&policy).is_none());
+        drop(first);
+
+        // half of the active requests
+        let _admitted = (0..4).map(|_| state.admit().unwrap()).collect::<Vec<_>>();
+        let _retries = (0..2)
+            .map(|_| state.begin_retry(&policy).unwrap())
+            .collect::<Vec<_>>();
+        assert!(state.begin_retry(&policy).is_none());
+    }
+
+    #[test]
+    fn circuit_breaker_caps_the_budget() {
+        let policy = policy(json!({"min_retries": 5}));
+        let state = servant(json!({"routes": [], "endpoints": ["10.0.0.1:80"],
+            "circuit_breaker": {"max_retries": 1}}))
+        .state;
+        let _retry = state.begin_retry(&policy).unwrap();
+        assert!(state.begin_retry(&policy).is_none());
+    }
+}
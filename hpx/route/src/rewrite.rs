@@ -0,0 +1,140 @@
+use crate::{PathParams, RouteKind, RoutePath};
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::fmt;
+
+/// Rewrite of the path by a regex, `substitution` may refer to its capture
+/// groups as `$1` or `${name}`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RegexRewrite {
+    #[serde(rename = "pattern")]
+    pub pattern: RewriteRegex,
+    #[serde(rename = "substitution")]
+    pub substitution: String,
+}
+
+/// A regex replacing any of its matches, compiled when the route is parsed.
+#[derive(Clone)]
+pub struct RewriteRegex(Regex);
+
+impl fmt::Debug for RewriteRegex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0.as_str())
+    }
+}
+
+impl Serialize for RewriteRegex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for RewriteRegex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern)
+            .map(RewriteRegex)
+            .map_err(|e| de::Error::custom(format!("Invalid regex '{}': {}", pattern, e)))
+    }
+}
+
+impl RoutePath {
+    /// The path to forward a request for `path` with, `None` when the route
+    /// doesn't rewrite paths or its `regex_rewrite` doesn't match. `{name}` in
+    /// the rewrite is replaced by the path variable `name`.
+    pub fn rewrite_path(&self, path: &str, params: &PathParams) -> Option<String> {
+        let rewritten = if let Some(prefix) = &self.prefix_rewrite {
+            match self.kind {
+                RouteKind::Fuzzy => {
+                    let rest = path.get(self.path.len()..).unwrap_or_default();
+                    let rest = match prefix.ends_with('/') {
+                        true => rest.strip_prefix('/').unwrap_or(rest),
+                        false => rest,
+                    };
+                    format!("{}{}", prefix, rest)
+                }
+                // the route matched the whole path
+                _ => prefix.clone(),
+            }
+        } else {
+            let rewrite = self.regex_rewrite.as_ref()?;
+            let substitution = rewrite.substitution.as_str();
+            match rewrite.pattern.0.replace_all(path, substitution) {
+                Cow::Borrowed(_) => return None,
+                Cow::Owned(rewritten) => rewritten,
+            }
+        };
+        let mut rewritten = params.0.iter().fold(rewritten, |path, (name, value)| {
+            path.replace(&format!("{{{}}}", name), value)
+        });
+        if !rewritten.starts_with('/') {
+            rewritten.insert(0, '/');
+        }
+        Some(rewritten)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{PathParams, RoutePath};
+    use serde_json::json;
+
+    fn route(route: serde_json::Value) -> RoutePath {
+        serde_json::from_value(route).unwrap()
+    }
+
+    fn params(params: &[(&str, &str)]) -> PathParams {
+        PathParams(
+            params
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn prefix_rewrite_of_fuzzy_routes_keeps_the_rest() {
+        let route_to =
+            |prefix| route(json!({"path": "/api/", "kind": "fuzzy", "prefix_rewrite": prefix}));
+        let none = PathParams::default();
+        assert_eq!(
+            route_to("/").rewrite_path("/api/users", &none),
+            Some("/users".into())
+        );
+        assert_eq!(
+            route_to("/v2").rewrite_path("/api/users", &none),
+            Some("/v2users".into())
+        );
+        assert_eq!(
+            route_to("/v2/").rewrite_path("/api/users", &none),
+            Some("/v2/users".into())
+        );
+        assert_eq!(route_to("/").rewrite_path("/api/", &none), Some("/".into()));
+    }
+
+    #[test]
+    fn prefix_rewrite_of_templates_fills_in_variables() {
+        let route = route(json!({"path": "/users/{id}", "kind": "template",
+            "prefix_rewrite": "/v2/users/{id}/profile"}));
+        assert_eq!(
+            route.rewrite_path("/users/7", &params(&[("id", "7")])),
+            Some("/v2/users/7/profile".into())
+        );
+    }
+
+    #[test]
+    fn regex_rewrite_substitutes_matches() {
+        let route = route(json!({"path": "/r/", "kind": "fuzzy",
+            "regex_rewrite": {"pattern": "^/r/(\\w+)", "substitution": "$1"}}));
+        let none = PathParams::default();
+        assert_eq!(route.rewrite_path("/r/abc/d", &none), Some("/abc/d".into()));
+        assert_eq!(route.rewrite_path("/x/abc", &none), None);
+    }
+
+    #[test]
+    fn no_rewrite() {
+        let route = route(json!({"path": "/a", "kind": "precise"}));
+        assert_eq!(route.rewrite_path("/a", &PathParams::default()), None);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::hash::hash;
+use crate::outlier::now_millis;
+use crate::{EndpointsMap, Route, RouteEndpoint};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// The endpoints of a route as installed, kept for diffs and rollbacks.
+#[derive(Serialize, Debug, Clone)]
+pub struct RouteSnapshot {
+    pub version: u64,
+    /// milliseconds since the unix epoch
+    #[serde(rename = "timestamp_ms")]
+    pub timestamp: u64,
+    /// hex hash of the endpoints, equal for equal content
+    pub hash: String,
+    #[serde(skip)]
+    pub endpoints: EndpointsMap,
+}
+
+impl RouteSnapshot {
+    pub fn new(route: &Route) -> Self {
+        Self {
+            version: route.version,
+            timestamp: now_millis(),
+            hash: content_hash(&route.endpoints),
+            endpoints: route.endpoints.clone(),
+        }
+    }
+
+    /// Servants added, removed and changed from `self` to `to`.
+    pub fn diff<'a>(&'a self, to: &'a RouteSnapshot) -> RouteDiff<'a> {
+        let mut diff = RouteDiff::default();
+        for (name, ep) in self.endpoints.iter() {
+            match to.endpoints.get(name) {
+                None => {
+                    diff.removed.insert(name, ep);
+                }
+                Some(other) if !same_endpoint(ep, other) => {
+                    diff.changed.insert(
+                        name,
+                        ChangedServant {
+                            from: ep,
+                            to: other,
+                        },
+                    );
+                }
+                Some(_) => {}
+            }
+        }
+        for (name, ep) in to.endpoints.iter() {
+            if !self.endpoints.contains_key(name) {
+                diff.added.insert(name, ep);
+            }
+        }
+        diff
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct RouteDiff<'a> {
+    pub added: BTreeMap<&'a str, &'a RouteEndpoint>,
+    pub removed: BTreeMap<&'a str, &'a RouteEndpoint>,
+    pub changed: BTreeMap<&'a str, ChangedServant<'a>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChangedServant<'a> {
+    pub from: &'a RouteEndpoint,
+    pub to: &'a RouteEndpoint,
+}
+
+fn same_endpoint(a: &RouteEndpoint, b: &RouteEndpoint) -> bool {
+    serde_json::to_string(a).ok() == serde_json::to_string(b).ok()
+}
+
+fn content_hash(endpoints: &EndpointsMap) -> String {
+    // sorted by servant so the hash doesn't depend on the map's order
+    let sorted = endpoints.iter().collect::<BTreeMap<_, _>>();
+    let content = serde_json::to_string(&sorted).unwrap_or_default();
+    format!("{:016x}", hash(&content))
+}
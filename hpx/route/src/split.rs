@@ -0,0 +1,39 @@
+use crate::RouteRule;
+use hyper::http::Request;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+/// A servant taking a share of a route's traffic.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SplitTarget {
+    #[serde(rename = "servant")]
+    pub servant: String,
+    /// share of the traffic, relative to the other targets' weights
+    #[serde(rename = "weight")]
+    pub weight: u32,
+}
+
+impl RouteRule {
+    /// The servant taking the request, one of the split targets picked by
+    /// weight when the route is split, its own servant otherwise. With a
+    /// `sticky` key requests carrying the same key land on the same servant.
+    pub fn pick_servant<B>(&self, req: &Request<B>, peer: SocketAddr) -> usize {
+        let total = self.split.iter().map(|(_, w)| *w as u64).sum::<u64>();
+        if total == 0 {
+            return self.servant;
+        }
+        let sticky = self.path.sticky.as_ref().and_then(|k| k.hash(req, peer));
+        let mut point = match sticky {
+            Some(hash) => hash % total,
+            None => rand::thread_rng().gen_range(0..total),
+        };
+        for (servant, weight) in self.split.iter() {
+            if point < *weight as u64 {
+                return *servant;
+            }
+            point -= *weight as u64;
+        }
+        self.servant
+    }
+}
@@ -0,0 +1,30 @@
+//! Fixtures shared by the unit tests.
+use crate::{RouteEndpoint, Servant, ServantState, Server};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// A servant registered as `ep`, e.g. `{"routes": [], "endpoints": [..]}`,
+/// built without validating it.
+pub(crate) fn servant(ep: Value) -> Servant {
+    let ep: RouteEndpoint = serde_json::from_value(ep).unwrap();
+    let servers = ep
+        .endpoints
+        .iter()
+        .map(|e| Server {
+            addr: e.addr().into(),
+            weight: e.weight(),
+        })
+        .collect::<Vec<_>>();
+    Servant {
+        name: "test".into(),
+        lb_policy: ep.lb_policy,
+        hash_key: ep.hash_key.clone(),
+        health_check: ep.health_check.clone(),
+        outlier_detection: ep.outlier_detection.clone(),
+        circuit_breaker: ep.circuit_breaker.clone(),
+        request_headers: ep.request_headers.clone(),
+        response_headers: ep.response_headers.clone(),
+        state: Arc::new(ServantState::new(&servers, &ep)),
+        servers,
+    }
+}
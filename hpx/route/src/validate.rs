@@ -0,0 +1,368 @@
+use crate::{
+    path_regex, variables, EndpointsMap, HeaderRules, RouteKind, ServiceRoute, HEADER_VARIABLES,
+};
+use hpx_error::error::AppResponseError;
+use hyper::http::uri::Authority;
+use hyper::http::{HeaderName, HeaderValue};
+use std::collections::HashMap;
+use std::fmt;
+
+/// The field errors of a rejected registration.
+#[derive(Debug)]
+pub struct ValidationError(pub Vec<AppResponseError>);
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let errors = self
+            .0
+            .iter()
+            .map(|e| format!("{}: {}", e.field.as_deref().unwrap_or("-"), e.message))
+            .collect::<Vec<_>>();
+        write!(f, "{}", errors.join(", "))
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl ValidationError {
+    /// The validation errors carried by an `io::Error`, if any.
+    pub fn of(e: &std::io::Error) -> Option<&ValidationError> {
+        e.get_ref()?.downcast_ref::<ValidationError>()
+    }
+}
+
+/// Check the registered routes, every problem found is reported.
+pub fn validate(ep: &EndpointsMap) -> Result<(), ValidationError> {
+    let mut errors = Vec::new();
+    let mut claimed: HashMap<(String, RouteKind, &str, String), &str> = HashMap::new();
+    let mut names = ep.keys().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        let servant = &ep[name];
+        if name.is_empty() {
+            errors.push(invalid("servant", "must not be empty"));
+        }
+        if servant.endpoints.is_empty() {
+            let field = format!("{}.endpoints", name);
+            errors.push(invalid(&field, "must not be empty"));
+        }
+        let weighted = servant.endpoints.iter().any(|e| e.weight().is_some());
+        if weighted
+            && servant
+                .endpoints
+                .iter()
+                .all(|e| e.weight().unwrap_or(1) <= 0)
+        {
+            let field = format!("{}.endpoints", name);
+            errors.push(invalid(&field, "must have a positive weight"));
+        }
+        for (i, endpoint) in servant.endpoints.iter().enumerate() {
+            if !is_host_port(endpoint.addr()) {
+                let field = format!("{}.endpoints[{}]", name, i);
+                errors.push(invalid(&field, "must be host:port"));
+            }
+        }
+        header_rules(
+            &format!("{}.request_headers", name),
+            &servant.request_headers,
+            &mut errors,
+        );
+        header_rules(
+            &format!("{}.response_headers", name),
+            &servant.response_headers,
+            &mut errors,
+        );
+        if let Some(check) = &servant.health_check {
+            let field = format!("{}.health_check", name);
+            if check.interval == 0 {
+                errors.push(invalid(&format!("{}.interval", field), "must be positive"));
+            }
+            if check.timeout == 0 || check.timeout > check.interval {
+                let message = "must be positive and not above interval";
+                errors.push(invalid(&format!("{}.timeout", field), message));
+            }
+        }
+        for (i, route) in servant.routes.iter().enumerate() {
+            let field = format!("{}.routes[{}].path", name, i);
+            // a regex is anchored to the whole path and may start with `^`
+            if route.kind != RouteKind::Regex && !route.path.starts_with('/') {
+                errors.push(invalid(&field, "must start with '/'"));
+            }
+            if let Some(Err(e)) = path_regex(route.kind, &route.path) {
+                errors.push(invalid(&field, &e));
+            }
+            for (j, target) in route.split.iter().enumerate() {
+                if !ep.contains_key(&target.servant) {
+                    let field = format!("{}.routes[{}].split[{}].servant", name, i, j);
+                    let message = format!("{} is not registered", target.servant);
+                    errors.push(invalid(&field, &message));
+                }
+            }
+            if let Some(prefix) = &route.prefix_rewrite {
+                let field = format!("{}.routes[{}].prefix_rewrite", name, i);
+                if !prefix.starts_with('/') {
+                    errors.push(invalid(&field, "must start with '/'"));
+                }
+                if route.regex_rewrite.is_some() {
+                    errors.push(invalid(&field, "can't be combined with regex_rewrite"));
+                }
+            }
+            if let Some(host) = &route.host_rewrite {
+                if HeaderValue::from_str(host).is_err() || host.is_empty() {
+                    let field = format!("{}.routes[{}].host_rewrite", name, i);
+                    errors.push(invalid(&field, "must be a host"));
+                }
+            }
+            let field = format!("{}.routes[{}]", name, i);
+            for (j, spec) in route.middlewares.iter().flatten().enumerate() {
+                if spec.name.is_empty() {
+                    let field = format!("{}.middlewares[{}].name", field, j);
+                    errors.push(invalid(&field, "must not be empty"));
+                }
+            }
+            header_rules(
+                &format!("{}.request_headers", field),
+                &route.request_headers,
+                &mut errors,
+            );
+            header_rules(
+                &format!("{}.response_headers", field),
+                &route.response_headers,
+                &mut errors,
+            );
+            if let Some(mirror) = &route.mirror {
+                let field = format!("{}.routes[{}].mirror", name, i);
+                if !ep.contains_key(&mirror.servant) {
+                    let message = format!("{} is not registered", mirror.servant);
+                    errors.push(invalid(&format!("{}.servant", field), &message));
+                }
+                if mirror.percent > 100 {
+                    errors.push(invalid(&format!("{}.percent", field), "must be 0-100"));
+                }
+                if mirror.timeout == 0 {
+                    errors.push(invalid(&format!("{}.timeout", field), "must be positive"));
+                }
+            }
+            if !route.split.is_empty() && route.split.iter().all(|t| t.weight == 0) {
+                let field = format!("{}.routes[{}].split", name, i);
+                errors.push(invalid(&field, "must have a positive weight"));
+            }
+            for (j, host) in route.hosts.iter().enumerate() {
+                if !is_host(host) {
+                    let field = format!("{}.routes[{}].hosts[{}]", name, i, j);
+                    errors.push(invalid(&field, "must be a host name or *.domain"));
+                }
+            }
+            let hosts = match route.hosts.is_empty() {
+                true => vec![String::new()],
+                false => route.hosts.iter().map(|h| h.to_ascii_lowercase()).collect(),
+            };
+            // routes on a path only conflict when their conditions are the same
+            let conditions = serde_json::to_string(&(&route.methods, &route.headers, &route.query))
+                .unwrap_or_default();
+            for host in hosts {
+                let key = (
+                    host.clone(),
+                    route.kind,
+                    route.path.as_str(),
+                    conditions.clone(),
+                );
+                match claimed.insert(key, name) {
+                    Some(other) if other != name => {
+                        let message = match host.is_empty() {
+                            true => format!("{} is already routed to {}", route.path, other),
+                            false => {
+                                format!("{}{} is already routed to {}", host, route.path, other)
+                            }
+                        };
+                        errors.push(invalid(&field, &message));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(ValidationError(errors)),
+    }
+}
+
+impl ServiceRoute {
+    /// Check a registration payload, including servants registered twice.
+    pub fn validate(routes: &[ServiceRoute]) -> Result<EndpointsMap, ValidationError> {
+        let mut errors = Vec::new();
+        let mut ep = EndpointsMap::with_capacity(routes.len());
+        for (i, r) in routes.iter().enumerate() {
+            if ep.insert(r.servant.clone(), r.endpoint.clone()).is_some() {
+                let message = format!("{} is registered more than once", r.servant);
+                errors.push(invalid(&format!("[{}].servant", i), &message));
+            }
+        }
+        if let Err(ValidationError(more)) = validate(&ep) {
+            errors.extend(more);
+        }
+        match errors.is_empty() {
+            true => Ok(ep),
+            false => Err(ValidationError(errors)),
+        }
+    }
+}
+
+fn is_host_port(addr: &str) -> bool {
+    match addr.parse::<Authority>() {
+        Ok(authority) => {
+            !addr.contains('@') && !authority.host().is_empty() && authority.port_u16().is_some()
+        }
+        Err(_) => false,
+    }
+}
+
+fn is_host(host: &str) -> bool {
+    let name = host.strip_prefix("*.").unwrap_or(host);
+    !name.is_empty() && !name.contains(['*', ':']) && name.parse::<Authority>().is_ok()
+}
+
+fn header_rules(field: &str, rules: &HeaderRules, errors: &mut Vec<AppResponseError>) {
+    let values = rules.set.iter().chain(&rules.add).chain(&rules.append);
+    for (name, value) in values {
+        let field = format!("{}.{}", field, name);
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            errors.push(invalid(&field, "must be a header name"));
+        }
+        let unknown = variables(value)
+            .into_iter()
+            .filter(|v| !HEADER_VARIABLES.contains(v))
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            let message = format!("unknown variables {}", unknown.join(", "));
+            errors.push(invalid(&field, &message));
+        } else if HeaderValue::from_str(value).is_err() {
+            errors.push(invalid(&field, "must be a header value"));
+        }
+    }
+    for name in &rules.remove {
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            let field = format!("{}.remove", field);
+            errors.push(invalid(&field, "must be header names"));
+        }
+    }
+}
+
+fn invalid(field: &str, message: &str) -> AppResponseError {
+    AppResponseError::invalid_field(field.to_owned(), message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn fields(routes: serde_json::Value) -> Vec<String> {
+        let routes: Vec<ServiceRoute> = serde_json::from_value(routes).unwrap();
+        match ServiceRoute::validate(&routes) {
+            Ok(_) => vec![],
+            Err(e) => e.0.into_iter().filter_map(|e| e.field).collect(),
+        }
+    }
+
+    #[test]
+    fn endpoints_must_be_host_port() {
+        let routes = json!([
+            {"servant": "a", "routes": [], "endpoints": []},
+            {"servant": "b", "routes": [], "endpoints": ["10.0.0.1", "user@h:80", "h:80"]}
+        ]);
+        assert_eq!(
+            fields(routes),
+            vec!["a.endpoints", "b.endpoints[0]", "b.endpoints[1]"]
+        );
+    }
+
+    #[test]
+    fn servants_are_registered_once() {
+        let routes = json!([
+            {"servant": "a", "routes": [], "endpoints": ["h:80"]},
+            {"servant": "a", "routes": [], "endpoints": ["h:81"]}
+        ]);
+        assert_eq!(fields(routes), vec!["[1].servant"]);
+    }
+
+    #[test]
+    fn paths_are_routed_to_one_servant() {
+        let route = |servant, path, methods| {
+            json!({"servant": servant, "endpoints": ["h:80"],
+                "routes": [{"path": path, "kind": "precise", "methods": methods}]})
+        };
+        let routes = json!([route("a", "/a", json!([])), route("b", "/a", json!([]))]);
+        assert_eq!(fields(routes), vec!["b.routes[0]"]);
+        let routes = json!([
+            route("a", "/a", json!(["GET"])),
+            route("b", "/a", json!([]))
+        ]);
+        assert!(fields(routes).is_empty());
+        let routes = json!([route("a", "a", json!([]))]);
+        assert_eq!(fields(routes), vec!["a.routes[0].path"]);
+    }
+
+    #[test]
+    fn regex_paths_may_be_anchored() {
+        let route = |kind, path| {
+            json!([{"servant": "a", "endpoints": ["h:80"],
+                "routes": [{"path": path, "kind": kind}]}])
+        };
+        assert!(fields(route("regex", "^/users/[0-9]+$")).is_empty());
+        assert_eq!(
+            fields(route("regex", "^/users/(")),
+            vec!["a.routes[0].path"]
+        );
+        assert_eq!(
+            fields(route("template", "users/{id}")),
+            vec!["a.routes[0].path"]
+        );
+    }
+
+    #[test]
+    fn split_and_mirror_servants_must_be_registered() {
+        let routes = json!([{"servant": "a", "endpoints": ["h:80"], "routes": [{
+            "path": "/a", "kind": "precise",
+            "split": [{"servant": "a", "weight": 1}, {"servant": "b", "weight": 1}],
+            "mirror": {"servant": "c", "percent": 101, "timeout": 0}
+        }]}]);
+        assert_eq!(
+            fields(routes),
+            vec![
+                "a.routes[0].split[1].servant",
+                "a.routes[0].mirror.servant",
+                "a.routes[0].mirror.percent",
+                "a.routes[0].mirror.timeout"
+            ]
+        );
+    }
+
+    #[test]
+    fn weights_must_leave_a_server_taking_traffic() {
+        let routes = json!([{"servant": "a", "routes": [], "endpoints": [
+            {"addr": "10.0.0.1:80", "weight": 0}, {"addr": "10.0.0.2:80", "weight": -1}
+        ]}]);
+        assert_eq!(fields(routes), vec!["a.endpoints"]);
+        let routes = json!([{"servant": "a", "routes": [], "endpoints": [
+            {"addr": "10.0.0.1:80", "weight": 0}, "10.0.0.2:80"
+        ]}]);
+        assert!(fields(routes).is_empty());
+    }
+
+    #[test]
+    fn health_check_timeout_fits_in_the_interval() {
+        let servant = |interval, timeout| {
+            json!([{"servant": "a", "routes": [], "endpoints": ["10.0.0.1:80"],
+                "health_check": {"path": "/health", "interval": interval, "timeout": timeout}}])
+        };
+        assert!(fields(servant(5, 5)).is_empty());
+        assert_eq!(fields(servant(5, 6)), vec!["a.health_check.timeout"]);
+        assert_eq!(fields(servant(5, 0)), vec!["a.health_check.timeout"]);
+        assert_eq!(
+            fields(servant(0, 0)),
+            vec!["a.health_check.interval", "a.health_check.timeout"]
+        );
+    }
+}
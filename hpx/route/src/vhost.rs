@@ -0,0 +1,229 @@
+use crate::{path_regex, PathParams, RouteKind, RouteMap, RouteRadixTrie, RouteRules};
+use hyper::http::header::HOST;
+use hyper::http::Request;
+use radix_trie::TrieCommon;
+use regex::{Regex, RegexSet};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// The precise and fuzzy routes of one host, indexing into `Route.rules`.
+#[derive(Debug, Default)]
+pub struct VirtualHost {
+    pub rmap: RouteMap,
+    pub rtrie: RouteRadixTrie,
+    /// whole-path regexes of the regex and template routes, with their
+    /// `(specificity, rule)`
+    pub patterns: Vec<(String, (usize, usize))>,
+    /// `patterns` compiled into one set to match them all at once, and one by
+    /// one to capture their variables
+    set: Option<RegexSet>,
+    regexes: Vec<Regex>,
+}
+
+impl VirtualHost {
+    /// Add a `(specificity, rule)` on a path, after the rules of the path that
+    /// are at least as specific.
+    pub fn insert(&mut self, kind: RouteKind, path: &str, rule: (usize, usize)) {
+        let rules = match kind {
+            RouteKind::Regex | RouteKind::Template => {
+                // invalid patterns are rejected by validation
+                if let Some(Ok(regex)) = path_regex(kind, path) {
+                    self.patterns.push((regex, rule));
+                }
+                return;
+            }
+            RouteKind::Precise => self.rmap.entry(path.to_owned()).or_default(),
+            RouteKind::Fuzzy => {
+                if self.rtrie.get(path).is_none() {
+                    self.rtrie.insert(path.to_owned(), RouteRules::new());
+                }
+                self.rtrie.get_mut(path).unwrap()
+            }
+        };
+        let pos = rules.partition_point(|(specificity, _)| *specificity >= rule.0);
+        rules.insert(pos, rule);
+    }
+
+    fn compile(&mut self) -> Result<(), regex::Error> {
+        if self.patterns.is_empty() {
+            return Ok(());
+        }
+        self.set = Some(RegexSet::new(self.patterns.iter().map(|(p, _)| p))?);
+        self.regexes = self
+            .patterns
+            .iter()
+            .map(|(p, _)| Regex::new(p))
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    /// The most specific rule of a path whose conditions `matches` and the
+    /// variables it captured. Precise routes go first, then regex and template
+    /// routes, then fuzzy prefixes from the longest. Among equally specific
+    /// rules the first one inserted wins.
+    pub fn find<F: Fn(usize) -> bool>(
+        &self,
+        path: &str,
+        matches: F,
+    ) -> Option<(usize, PathParams)> {
+        let first = |rules: &RouteRules| rules.iter().map(|(_, i)| *i).find(|&i| matches(i));
+        if let Some(index) = self.rmap.get(path).and_then(first) {
+            return Some((index, PathParams::default()));
+        }
+        if let Some(set) = &self.set {
+            let mut hits = set.matches(path).into_iter().collect::<Vec<_>>();
+            hits.sort_by_key(|&i| {
+                let (specificity, rule) = self.patterns[i].1;
+                (Reverse(specificity), rule)
+            });
+            if let Some(i) = hits.into_iter().find(|&i| matches(self.patterns[i].1 .1)) {
+                let params = PathParams::capture(&self.regexes[i], path);
+                return Some((self.patterns[i].1 .1, params));
+            }
+        }
+        let mut key = path;
+        while let Some(prefix) = self.rtrie.get_ancestor(key) {
+            let len = prefix.key()?.len();
+            if let Some(index) = prefix.value().and_then(first) {
+                return Some((index, PathParams::default()));
+            }
+            if len == 0 {
+                return None;
+            }
+            key = &path[..len - 1];
+        }
+        None
+    }
+}
+
+/// Virtual hosts by name, `*.example.internal` names match any subdomain and
+/// requests matching no host go to the default one.
+#[derive(Debug, Default)]
+pub struct VirtualHosts {
+    pub exact: HashMap<String, VirtualHost>,
+    /// wildcard suffixes like `.example.internal`, longest first
+    pub wildcard: Vec<(String, VirtualHost)>,
+    pub default: VirtualHost,
+}
+
+impl VirtualHosts {
+    /// The virtual host named `host`, created when missing, `None` names the
+    /// default one.
+    pub fn entry(&mut self, host: Option<&str>) -> &mut VirtualHost {
+        let host = match host {
+            Some(host) => host.to_ascii_lowercase(),
+            None => return &mut self.default,
+        };
+        match host.strip_prefix('*') {
+            Some(suffix) => {
+                let pos = match self.wildcard.iter().position(|(s, _)| s == suffix) {
+                    Some(pos) => pos,
+                    None => {
+                        let pos = self
+                            .wildcard
+                            .partition_point(|(s, _)| s.len() >= suffix.len());
+                        self.wildcard
+                            .insert(pos, (suffix.to_owned(), VirtualHost::default()));
+                        pos
+                    }
+                };
+                &mut self.wildcard[pos].1
+            }
+            None => self.exact.entry(host).or_default(),
+        }
+    }
+
+    /// Compile the regex and template routes of every virtual host.
+    pub fn compile(&mut self) -> Result<(), regex::Error> {
+        self.default.compile()?;
+        for vhost in self.exact.values_mut() {
+            vhost.compile()?;
+        }
+        for (_, vhost) in self.wildcard.iter_mut() {
+            vhost.compile()?;
+        }
+        Ok(())
+    }
+
+    /// The virtual host serving `host`, exact names before wildcards.
+    pub fn select(&self, host: Option<&str>) -> &VirtualHost {
+        let host = match host {
+            Some(host) => host.to_ascii_lowercase(),
+            None => return &self.default,
+        };
+        if let Some(vhost) = self.exact.get(&host) {
+            return vhost;
+        }
+        self.wildcard
+            .iter()
+            .find(|(suffix, _)| host.ends_with(suffix.as_str()) && host.len() > suffix.len())
+            .map_or(&self.default, |(_, vhost)| vhost)
+    }
+}
+
+/// The host a request is for, from `:authority` or the `Host` header, without
+/// its port.
+pub fn request_host<B>(req: &Request<B>) -> Option<&str> {
+    let authority = match req.uri().authority() {
+        Some(authority) => authority.as_str(),
+        None => req.headers().get(HOST)?.to_str().ok()?,
+    };
+    let host = authority.rsplit('@').next()?;
+    // keep the brackets of an IPv6 literal, strip a trailing `:port`
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => Some(&host[..i]),
+        _ => Some(host),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users() -> VirtualHost {
+        let mut vhost = VirtualHost::default();
+        vhost.insert(RouteKind::Fuzzy, "/users/", (0, 0));
+        vhost.insert(RouteKind::Template, "/users/{id}", (0, 1));
+        vhost.insert(RouteKind::Regex, "/users/(?P<id>[0-9]+)", (1, 2));
+        vhost.insert(RouteKind::Precise, "/users/me", (0, 3));
+        vhost.insert(RouteKind::Precise, "/users/me", (1, 4));
+        vhost.compile().unwrap();
+        vhost
+    }
+
+    fn find(vhost: &VirtualHost, path: &str, skip: &[usize]) -> Option<(usize, String)> {
+        vhost
+            .find(path, |i| !skip.contains(&i))
+            .map(|(i, params)| (i, params.to_string()))
+    }
+
+    #[test]
+    fn precise_before_patterns_before_prefixes() {
+        let vhost = users();
+        assert_eq!(find(&vhost, "/users/me", &[]), Some((4, "".into())));
+        assert_eq!(
+            find(&vhost, "/users/me", &[3, 4]),
+            Some((1, "id=me".into()))
+        );
+        assert_eq!(find(&vhost, "/users/me/x", &[]), Some((0, "".into())));
+        assert_eq!(find(&vhost, "/users/me/x", &[0]), None);
+    }
+
+    #[test]
+    fn most_specific_pattern_wins() {
+        let vhost = users();
+        assert_eq!(find(&vhost, "/users/42", &[]), Some((2, "id=42".into())));
+        assert_eq!(find(&vhost, "/users/42", &[2]), Some((1, "id=42".into())));
+        assert_eq!(find(&vhost, "/users/bob", &[]), Some((1, "id=bob".into())));
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let mut vhost = VirtualHost::default();
+        vhost.insert(RouteKind::Fuzzy, "/", (0, 0));
+        vhost.insert(RouteKind::Fuzzy, "/api/", (0, 1));
+        assert_eq!(find(&vhost, "/api/users", &[]), Some((1, "".into())));
+        assert_eq!(find(&vhost, "/api/users", &[1]), Some((0, "".into())));
+        assert_eq!(find(&vhost, "/apis", &[]), Some((0, "".into())));
+    }
+}
@@ -8,7 +8,7 @@ pub fn random_set(size: usize, base: usize) -> BitSet {
     let mut chosen = Vec::with_capacity(base);
     let mut i = 0;
     while i < base {
-        chosen.insert(i as usize, i);
+        chosen.insert(i, i);
         s.insert(i);
         i += 1;
     }
@@ -23,5 +23,5 @@ pub fn random_set(size: usize, base: usize) -> BitSet {
         i += 1;
     }
 
-    return s;
+    s
 }
@@ -3,10 +3,10 @@ use mick_jaeger::TracesIn;
 use std::num::{NonZeroU128, NonZeroU64};
 use std::sync::Arc;
 
-pub const X_SAMPLING: &'static str = "x-sampling";
-pub const X_TRACE_ID: &'static str = "x-trace-id";
-pub const X_SPAN_ID: &'static str = "x-span-id";
-pub const X_PARENT_ID: &'static str = "x-parent-id";
+pub const X_SAMPLING: &str = "x-sampling";
+pub const X_TRACE_ID: &str = "x-trace-id";
+pub const X_SPAN_ID: &str = "x-span-id";
+pub const X_PARENT_ID: &str = "x-parent-id";
 
 #[derive(Copy, Clone)]
 pub struct Tracing {
@@ -28,11 +28,11 @@ pub async fn start_tracing(
     tokio::spawn(async move {
         loop {
             let buf = traces_out.next().await;
-            udp_socket.send(&buf).await;
+            let _ = udp_socket.send(&buf).await;
         }
     });
 
-    return Ok(traces_in);
+    Ok(traces_in)
 }
 
 pub fn set_tracing_header(trace: Tracing, sampling: bool, header: &mut HeaderMap<HeaderValue>) {